@@ -1,43 +1,485 @@
-use crate::structs::error::InvalidHashIdError;
-use crate::structs::bucket::Kbuckets;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::structs::announce::{Announce, AnnounceSchedule, AnnounceStatus};
+use crate::structs::bootstrap::Bootstrap;
+use crate::structs::clock::{Clock, SystemClock};
+use crate::structs::error::DhtError;
+use crate::structs::lookup::{Lookup, LookupKind};
+use crate::structs::bucket::{Insertion, Kbuckets};
 use crate::structs::message::*;
 use crate::structs::node::*;
+use crate::structs::peers::PeerStore;
+use crate::structs::token::{Token, TokenAuthority};
+use crate::structs::transaction::Transactions;
 use crate::structs::util::HashId;
-use crate::structs::token::TokenAuthority;
+
+pub type LookupId = usize;
+
+/// Outcome of a query or operation we started, reported through `DhtHandler::poll_event`.
+#[derive(Debug)]
+pub enum Event {
+    Response {
+        transaction: MessageId,
+        endpoint: Endpoint,
+        query: Query,
+        response: Response,
+    },
+    Error {
+        transaction: MessageId,
+        endpoint: Endpoint,
+        query: Query,
+        error: ErrorResponse,
+    },
+    Timeout {
+        transaction: MessageId,
+        endpoint: Endpoint,
+        query: Query,
+    },
+    Bootstrapped {
+        nodes: usize,
+    },
+    BootstrapFailed,
+    PeersFound {
+        lookup: LookupId,
+        info_hash: HashId,
+        peers: Vec<Endpoint>,
+    },
+    LookupFinished {
+        lookup: LookupId,
+        target: HashId,
+        nodes: Vec<Node>,
+    },
+    Announced {
+        lookup: LookupId,
+        info_hash: HashId,
+        accepted: usize,
+    },
+    /// A node stopped answering and one from the replacement cache took its place.
+    NodeReplaced {
+        failed: HashId,
+        replacement: Node,
+    },
+    /// A query couldn't be sent, the operation that wanted it carries on without it.
+    SendFailed {
+        endpoint: Endpoint,
+        error: DhtError,
+    },
+}
 
 #[derive(Debug)]
 pub struct DhtHandler {
     node: Node,
     buckets: Kbuckets,
-    identifier: String,
-    peers: PeerList,
-    signer: TokenAuthority
+    identifier: ClientIdentifier,
+    peers: PeerStore,
+    signer: TokenAuthority,
+    transactions: Transactions,
+    outbox: VecDeque<(Vec<u8>, SocketAddr)>,
+    events: VecDeque<Event>,
+    bootstrap: Option<Bootstrap>,
+    lookups: HashMap<LookupId, Lookup>,
+    lookup_queries: HashMap<MessageId, (LookupId, HashId)>,
+    next_lookup: LookupId,
+    announces: HashMap<LookupId, Announce>,
+    schedule: AnnounceSchedule,
+    clock: Arc<dyn Clock>
 }
 
 impl DhtHandler {
     pub fn new(node: Node) -> DhtHandler {
+        DhtHandler::with_clock(node, SystemClock)
+    }
+
+    /// All time dependent logic, from node states to token rotation, reads the time from the clock.
+    pub fn with_clock<C: Clock + 'static>(node: Node, clock: C) -> DhtHandler {
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+
         DhtHandler {
             node,
-            buckets: Kbuckets::new(),
-            identifier: "MW01".to_owned(),
-            peers: PeerList::new(),
-            signer: TokenAuthority::new()
+            buckets: Kbuckets::with_clock(node.node_id, clock.clone()),
+            identifier: b"MW01".to_vec(),
+            peers: PeerStore::new(),
+            signer: TokenAuthority::new(clock.now()),
+            transactions: Transactions::new(),
+            outbox: VecDeque::new(),
+            events: VecDeque::new(),
+            bootstrap: None,
+            lookups: HashMap::new(),
+            lookup_queries: HashMap::new(),
+            next_lookup: 0,
+            announces: HashMap::new(),
+            schedule: AnnounceSchedule::new(),
+            clock
+        }
+    }
+
+    pub fn ping(&mut self, endpoint: Endpoint) -> Result<MessageId, DhtError> {
+        self.send_query(endpoint, Query::Ping { id: self.node.node_id })
+    }
+
+    pub fn find_node(&mut self, endpoint: Endpoint, target: HashId) -> Result<MessageId, DhtError> {
+        self.send_query(endpoint, Query::FindNode { id: self.node.node_id, target })
+    }
+
+    pub fn get_peers(&mut self, endpoint: Endpoint, info_hash: HashId) -> Result<MessageId, DhtError> {
+        self.send_query(endpoint, Query::GetPeers { id: self.node.node_id, info_hash })
+    }
+
+    pub fn announce_peer(
+        &mut self,
+        endpoint: Endpoint,
+        info_hash: HashId,
+        port: u16,
+        implied_port: bool,
+        token: Token,
+    ) -> Result<MessageId, DhtError> {
+        self.send_query(endpoint, Query::AnnouncePeer {
+            id: self.node.node_id,
+            implied_port: Some(implied_port),
+            port,
+            token,
+            info_hash
+        })
+    }
+
+    /// Joins the network through the seeds, reported as `Event::Bootstrapped` or `Event::BootstrapFailed`.
+    pub fn bootstrap(&mut self, seeds: &[Endpoint]) {
+        let mut bootstrap = Bootstrap::new(self.buckets.len());
+        let mut endpoints = seeds.to_vec();
+        endpoints.extend(self.buckets.find_closest_nodes(&self.node.node_id).iter().map(|node| node.endpoint));
+
+        self.bootstrap_round(&mut bootstrap, endpoints);
+        self.bootstrap = Some(bootstrap);
+        self.bootstrap_progress();
+    }
+
+    /// Looks for the nodes closest to the target, reported as `Event::LookupFinished`.
+    pub fn find_node_lookup(&mut self, target: HashId) -> LookupId {
+        self.start_lookup(Lookup::new(LookupKind::FindNode, target))
+    }
+
+    /// Asks the network for peers of the info hash, they are reported as `Event::PeersFound`
+    /// while the lookup is running.
+    pub fn get_peers_lookup(&mut self, info_hash: HashId) -> LookupId {
+        self.start_lookup(Lookup::new(LookupKind::GetPeers, info_hash))
+    }
+
+    /// Announces that we have the info hash on the port to the closest nodes, reported as `Event::Announced`.
+    pub fn announce(&mut self, info_hash: HashId, port: u16, implied_port: bool) -> LookupId {
+        let id = self.insert_lookup(Lookup::new(LookupKind::GetPeers, info_hash));
+        self.announces.insert(id, Announce::new(info_hash, port, implied_port));
+        self.advance_lookup(id);
+
+        id
+    }
+
+    /// Keeps announcing the info hash, starting with the next tick.
+    pub fn track(&mut self, info_hash: HashId, port: u16, implied_port: bool) {
+        self.schedule.add(info_hash, port, implied_port);
+    }
+
+    pub fn untrack(&mut self, info_hash: &HashId) -> bool {
+        self.schedule.remove(info_hash)
+    }
+
+    pub fn announce_status(&self, info_hash: &HashId) -> Option<&AnnounceStatus> {
+        self.schedule.status(info_hash)
+    }
+
+    pub fn set_announce_interval(&mut self, interval: Duration) {
+        self.schedule.set_interval(interval);
+    }
+
+    /// How long peers announced to us are handed out without being announced again.
+    pub fn set_peer_ttl(&mut self, ttl: Duration) {
+        self.peers.set_ttl(ttl);
+    }
+
+    /// Bounds the memory spent on peers announced to us, see `PeerStore` for the eviction order.
+    pub fn set_peer_limits(&mut self, max_info_hashes: usize, max_peers_per_info_hash: usize) {
+        self.peers.set_limits(max_info_hashes, max_peers_per_info_hash);
+    }
+
+    pub fn start_lookup(&mut self, lookup: Lookup) -> LookupId {
+        let id = self.insert_lookup(lookup);
+        self.advance_lookup(id);
+
+        id
+    }
+
+    fn insert_lookup(&mut self, mut lookup: Lookup) -> LookupId {
+        let id = self.next_lookup;
+        self.next_lookup += 1;
+
+        lookup.add_candidates(&self.buckets.find_closest_nodes(&lookup.target()));
+        self.lookups.insert(id, lookup);
+
+        id
+    }
+
+    /// Datagrams that have to be sent, the handler itself never touches the network.
+    pub fn poll_outgoing(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        self.outbox.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Times out unanswered queries, rotates the token secret, forgets expired peers and starts
+    /// due announces and bucket refreshes, should be called regularly.
+    pub fn tick(&mut self) {
+        let now = self.clock.now();
+
+        self.signer.rotate_if_due(now);
+        self.peers.expire(now);
+        self.expire_transactions(now);
+
+        for (info_hash, port, implied_port) in self.schedule.due(now) {
+            self.announce(info_hash, port, implied_port);
+        }
+
+        for (target, questionables) in self.buckets.refresh() {
+            self.find_node_lookup(target);
+            self.ping_questionables(questionables);
         }
     }
 
-    pub fn handle_str(&mut self, input: String) -> Option<String> {
-        let endpoint = Endpoint::new("127.0.0.1", 4444).unwrap();
+    fn expire_transactions(&mut self, now: Instant) {
+        for (transaction, pending) in self.transactions.expire(now) {
+            let known = self.buckets.find_by_endpoint(&pending.endpoint).map(|node| node.node_id);
 
-        match Message::from_str(input) {
-            Ok(message) => Some(self.handel_message(message, endpoint).unwrap().unwrap().to_str().unwrap()),
-            Err(_) => {
-                println!("Can't parse message");
-                None
+            if let Some(id) = known {
+                if let Some(replacement) = self.buckets.mark_failed(&id) {
+                    self.events.push_back(Event::NodeReplaced { failed: id, replacement });
+                }
+            }
+
+            self.events.push_back(Event::Timeout {
+                transaction: transaction.clone(),
+                endpoint: pending.endpoint,
+                query: pending.query
+            });
+            self.query_finished(&transaction, None);
+        }
+    }
+
+    /// Hands the outcome of a query to the operation that sent it, `None` if it wasn't answered.
+    fn query_finished(&mut self, transaction: &[u8], response: Option<&Response>) {
+        if let Some(bootstrap) = &mut self.bootstrap {
+            if bootstrap.finish(transaction) {
+                self.bootstrap_progress();
+            }
+        }
+
+        let accepted = matches!(response, Some(Response::Empty { .. }));
+        let announce = self
+            .announces
+            .iter_mut()
+            .find_map(|(id, announce)| if announce.finish(transaction, accepted) { Some(*id) } else { None });
+
+        if let Some(id) = announce {
+            self.finish_announce(id);
+        }
+
+        if let Some((id, node_id)) = self.lookup_queries.remove(transaction) {
+            if let Some(lookup) = self.lookups.get_mut(&id) {
+                let own_id = self.node.node_id;
+                let closer = |nodes: &CompactNodeList| {
+                    nodes.nodes().iter().filter(|node| node.node_id != own_id).copied().collect::<Vec<Node>>()
+                };
+
+                match response {
+                    Some(Response::FoundNodes { nodes, .. }) => lookup.responded(&node_id, &closer(nodes)),
+                    Some(Response::FoundPeerNodes { nodes, token, .. }) => {
+                        lookup.set_token(&node_id, token.clone());
+                        lookup.responded(&node_id, &closer(nodes));
+                    }
+                    Some(Response::FoundPeers { values, token, nodes, .. }) => {
+                        lookup.set_token(&node_id, token.clone());
+                        lookup.responded(&node_id, &nodes.as_ref().map(closer).unwrap_or_default());
+
+                        let peers = lookup.add_peers(values);
+
+                        if !peers.is_empty() {
+                            self.events.push_back(Event::PeersFound {
+                                lookup: id,
+                                info_hash: lookup.target(),
+                                peers
+                            });
+                        }
+                    }
+                    Some(Response::Empty { .. }) => lookup.responded(&node_id, &[]),
+                    None => lookup.failed(&node_id),
+                }
+
+                self.advance_lookup(id);
             }
         }
     }
 
-    fn handel_message(&mut self, message: Message, endpoint: Endpoint) -> Result<Option<Message>, InvalidHashIdError> {
+    fn advance_lookup(&mut self, id: LookupId) {
+        let (kind, target, queries) = match self.lookups.get_mut(&id) {
+            Some(lookup) => (lookup.kind(), lookup.target(), lookup.next_queries()),
+            None => return,
+        };
+
+        for node in queries {
+            let sent = match kind {
+                LookupKind::FindNode => self.find_node(node.endpoint, target),
+                LookupKind::GetPeers => self.get_peers(node.endpoint, target),
+            };
+
+            match sent {
+                Ok(transaction) => {
+                    self.lookup_queries.insert(transaction, (id, node.node_id));
+                }
+                Err(error) => {
+                    self.events.push_back(Event::SendFailed { endpoint: node.endpoint, error });
+
+                    if let Some(lookup) = self.lookups.get_mut(&id) {
+                        lookup.failed(&node.node_id);
+                    }
+                }
+            }
+        }
+
+        let finished = self.lookups.get(&id).is_some_and(Lookup::is_finished);
+
+        if finished {
+            if let Some(lookup) = self.lookups.remove(&id) {
+                self.events.push_back(Event::LookupFinished {
+                    lookup: id,
+                    target,
+                    nodes: lookup.closest()
+                });
+
+                self.send_announces(id, &lookup);
+            }
+        }
+    }
+
+    fn send_announces(&mut self, id: LookupId, lookup: &Lookup) {
+        let mut announce = match self.announces.remove(&id) {
+            Some(announce) => announce,
+            None => return,
+        };
+
+        for (node, token) in lookup.closest_with_tokens() {
+            match self.announce_peer(node.endpoint, announce.info_hash, announce.port, announce.implied_port, token) {
+                Ok(transaction) => announce.sent(transaction),
+                Err(error) => self.events.push_back(Event::SendFailed { endpoint: node.endpoint, error }),
+            }
+        }
+
+        self.announces.insert(id, announce);
+        self.finish_announce(id);
+    }
+
+    fn finish_announce(&mut self, id: LookupId) {
+        let finished = self.announces.get(&id).is_some_and(Announce::is_finished);
+
+        if finished {
+            if let Some(announce) = self.announces.remove(&id) {
+                self.schedule.finished(&announce.info_hash, announce.accepted(), self.clock.now());
+                self.events.push_back(Event::Announced {
+                    lookup: id,
+                    info_hash: announce.info_hash,
+                    accepted: announce.accepted()
+                });
+            }
+        }
+    }
+
+    fn bootstrap_round(&mut self, bootstrap: &mut Bootstrap, endpoints: Vec<Endpoint>) {
+        for endpoint in endpoints {
+            if !bootstrap.should_query(endpoint) {
+                continue;
+            }
+
+            match self.find_node(endpoint, self.node.node_id) {
+                Ok(transaction) => bootstrap.sent(transaction),
+                Err(error) => self.events.push_back(Event::SendFailed { endpoint, error }),
+            }
+        }
+    }
+
+    fn bootstrap_progress(&mut self) {
+        let mut bootstrap = match self.bootstrap.take() {
+            Some(bootstrap) => bootstrap,
+            None => return,
+        };
+
+        if !bootstrap.round_complete() {
+            self.bootstrap = Some(bootstrap);
+            return;
+        }
+
+        if bootstrap.next_round(self.buckets.len()) {
+            let closest = self.buckets.find_closest_nodes(&self.node.node_id);
+            self.bootstrap_round(&mut bootstrap, closest.iter().map(|node| node.endpoint).collect());
+
+            if !bootstrap.round_complete() {
+                self.bootstrap = Some(bootstrap);
+                return;
+            }
+        }
+
+        self.events.push_back(match self.buckets.len() {
+            0 => Event::BootstrapFailed,
+            nodes => Event::Bootstrapped { nodes },
+        });
+    }
+
+    fn send_query(&mut self, endpoint: Endpoint, query: Query) -> Result<MessageId, DhtError> {
+        let id = self.transactions.start(endpoint, query.clone(), self.clock.now())?;
+
+        let message = Message::Query {
+            id: id.clone(),
+            client: Some(self.identifier.clone()),
+            args: query
+        };
+
+        match message.to_bytes() {
+            Ok(encoded) => {
+                self.outbox.push_back((encoded, endpoint.to_socket_addr()));
+                Ok(id)
+            }
+            Err(e) => {
+                self.transactions.finish(&id, &endpoint);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn handle_datagram(&mut self, input: &[u8], source: SocketAddr) -> Option<Vec<u8>> {
+        let endpoint = Endpoint::from_socket_addr(source)?;
+
+        let reply = match Message::from_bytes(input) {
+            Ok(message) => {
+                let query_id = match &message {
+                    Message::Query { id, .. } | Message::UnknownQuery { id, .. } => Some(id.clone()),
+                    _ => None,
+                };
+
+                // anyone can send us garbage, so failures are answered but never logged
+                match (self.handle_message(message, endpoint), query_id) {
+                    (Ok(reply), _) => reply,
+                    (Err(_), Some(id)) => self.generic_error(&id),
+                    (Err(_), None) => None,
+                }
+            }
+            Err(DhtError::MalformedQuery(id, _)) => self.protocol_error(&id),
+            Err(_) => None,
+        }?;
+
+        reply.to_bytes().ok()
+    }
+
+    fn handle_message(&mut self, message: Message, endpoint: Endpoint) -> Result<Option<Message>, DhtError> {
         match message {
             Message::Query {
                 id,
@@ -45,143 +487,209 @@ impl DhtHandler {
                 client: _client,
             } => {
                 match args {
-                    Query::Ping { id: sender_string } => {
-                        let sender = HashId::from_str(sender_string)?;
-                        self.buckets.update_timestamps(&sender);
-                        self.response(&id, Response::Empty { id: self.node.node_id.to_str() })
+                    Query::Ping { id: sender } => {
+                        self.node_queried(sender, endpoint);
+                        self.response(&id, Response::Empty { id: self.node.node_id })
                     }
                     Query::GetPeers {
-                        id: sender_string,
-                        info_hash: info_hash_string,
+                        id: sender,
+                        info_hash,
                     } => {
-                        let sender = HashId::from_str(sender_string)?;
-                        let info_hash = HashId::from_str(info_hash_string)?;
-                        self.buckets.update_timestamps(&sender);
+                        self.node_queried(sender, endpoint);
+
+                        let peers = self.peers.get(&info_hash, self.clock.now());
 
-                        match self.peers.get(&info_hash) {
-                            Some (peers) => self.response(&id, Response::FoundPeers {
-                                id: self.node.node_id.to_str(),
+                        if peers.is_empty() {
+                            self.response(&id, Response::FoundPeerNodes {
+                                id: self.node.node_id,
                                 token: self.signer.sign(&endpoint),
-                                values: peers.iter().map(Endpoint::to_string).collect()
-                            }),
-                            None => self.response(&id, Response::FoundPeerNodes {
-                                id: self.node.node_id.to_str(),
+                                nodes: self.buckets.find_closest_nodes(&info_hash).into()
+                            })
+                        } else {
+                            self.response(&id, Response::FoundPeers {
+                                id: self.node.node_id,
                                 token: self.signer.sign(&endpoint),
-                                nodes: self.buckets.find_closest_nodes(&info_hash)
-                                    .unwrap_or(Vec::<Node>::new())
-                                    .iter().map(Node::to_str).collect()
+                                values: peers,
+                                nodes: None
                             })
                         }
                     }
                     Query::AnnouncePeer {
-                        id: sender_string,
-                        info_hash: info_hash_string,
+                        id: sender,
+                        info_hash,
                         implied_port,
                         mut port,
                         token,
                     } => {
-                        let sender = HashId::from_str(sender_string)?;
-                        let info_hash = HashId::from_str(info_hash_string)?;
-                        self.buckets.update_timestamps(&sender);
+                        self.node_queried(sender, endpoint);
 
                         if !self.signer.verify(&token, &endpoint) {
-                            return self.protocol_error(&id)
+                            return Ok(self.protocol_error(&id))
                         }
 
                         if implied_port.unwrap_or(false) {
                             port = endpoint.port
                         }
 
-                        let mut node = endpoint.clone();
-                        node.port = port;
+                        let mut peer = endpoint;
+                        peer.port = port;
 
-                        match self.peers.get_mut(&info_hash) {
-                            Some(node_list) => node_list.push(endpoint),
-                            None => {
-                                self.peers.insert(info_hash, vec!(node));
-                            }
-                        };
+                        self.peers.announce(info_hash, peer, self.clock.now());
 
                         self.response(&id, Response::Empty {
-                            id: self.node.node_id.to_str()
+                            id: self.node.node_id
                         })
                     }
-                    Query::FindNode { id: sender_string, target: target_string } => {
-                        let sender = HashId::from_str(sender_string)?;
-                        let target = HashId::from_str(target_string)?;
-                        self.buckets.update_timestamps(&sender);
+                    Query::FindNode { id: sender, target } => {
+                        self.node_queried(sender, endpoint);
 
-                        let mut closest = self.buckets.find_closest_nodes(&target)
-                            .unwrap_or(Vec::<Node>::new());
+                        let mut closest = self.buckets.find_closest_nodes(&target);
 
-                        if closest.len() > 0 && closest[0].node_id == target {
+                        if !closest.is_empty() && closest[0].node_id == target {
                             closest.truncate(1);
                         }
 
                         self.response(&id, Response::FoundNodes {
-                            id: self.node.node_id.to_str(),
-                            nodes: closest.iter().map(Node::to_str).collect()
+                            id: self.node.node_id,
+                            nodes: closest.into()
                         })
                     }
                 }
             }
+            Message::UnknownQuery { id, client: _, method: _ } => {
+                Ok(self.error(&id, 204, "Method Unknown"))
+            }
             Message::Response {
                 id,
-                client,
-                response,
+                client: _,
+                values,
             } => {
-                match response {
-                    Response::FoundPeers { id: _, token: _, values: _ } => {
-                        // nop
-                        Ok(None)
-                    }
-                    Response::FoundPeerNodes { id: _, token: _, nodes: _ } => {
-                        // nop
-                        Ok(None)
-                    }
-                    Response::FoundNodes { id: sender_string, nodes } => {
-                        let sender = HashId::from_str(sender_string);
-                        // check if we requested them
-                        // update node buckets
-                        Ok(None)
-                    }
-                    Response::Empty { id: sender_string } => {
-                        let sender = HashId::from_str(sender_string)?;
-                        self.buckets.update_timestamps(&sender);
-                        Ok(None)
+                let method = match self.transactions.get(&id, &endpoint) {
+                    Some(pending) => pending.query.method(),
+                    None => return Ok(None),
+                };
+
+                // a response that doesn't fit the query keeps the transaction open until it times out
+                let response = Response::from_values(method, values)?;
+                if let Some(pending) = self.transactions.finish(&id, &endpoint) {
+                    self.node_responded(response.id(), endpoint);
+
+                    match &response {
+                        Response::FoundNodes { nodes, .. }
+                        | Response::FoundPeerNodes { nodes, .. }
+                        | Response::FoundPeers { nodes: Some(nodes), .. } => self.learn_nodes(nodes.nodes()),
+                        _ => {}
                     }
+
+                    self.events.push_back(Event::Response {
+                        transaction: id.clone(),
+                        endpoint,
+                        query: pending.query,
+                        response: response.clone()
+                    });
+                    self.query_finished(&id, Some(&response));
                 }
+
+                Ok(None)
             }
             Message::Error {
                 id,
                 error,
                 client: _,
             } => {
-                println!("Recieved error message for message {} {:?}", id, error);
+                if let Some(pending) = self.transactions.finish(&id, &endpoint) {
+                    self.events.push_back(Event::Error {
+                        transaction: id.clone(),
+                        endpoint,
+                        query: pending.query,
+                        error
+                    });
+                    self.query_finished(&id, None);
+                }
+
                 Ok(None)
             }
         }
     }
 
-    fn response (&self, id: &String, response: Response) -> Result<Option<Message>, InvalidHashIdError> {
+    fn node_queried (&mut self, id: HashId, endpoint: Endpoint) {
+        if self.learn_node(id, endpoint) {
+            self.buckets.mark_queried(&id);
+        }
+    }
+
+    fn node_responded (&mut self, id: HashId, endpoint: Endpoint) {
+        if self.learn_node(id, endpoint) {
+            self.buckets.mark_responded(&id);
+        }
+    }
+
+    /// Offers nodes we were told about to the table, they stay questionable until they answer us.
+    fn learn_nodes (&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.learn_node(node.node_id, node.endpoint);
+        }
+    }
+
+    fn learn_node (&mut self, id: HashId, endpoint: Endpoint) -> bool {
+        if id == self.node.node_id {
+            return false;
+        }
+
+        // a full bucket far away from us keeps the node as a replacement and
+        // pings its questionable nodes, so the unresponsive ones can be evicted
+        match self.buckets.try_insert(Node::new(endpoint, id)) {
+            Ok(Insertion::Cached(questionables)) => {
+                self.ping_questionables(questionables);
+                true
+            }
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+
+    fn ping_questionables (&mut self, questionables: Vec<Node>) {
+        for node in questionables {
+            if self.transactions.is_pending(&node.endpoint) {
+                continue;
+            }
+
+            if let Err(error) = self.ping(node.endpoint) {
+                self.events.push_back(Event::SendFailed { endpoint: node.endpoint, error });
+            }
+        }
+    }
+
+    fn response (&self, id: &[u8], response: Response) -> Result<Option<Message>, DhtError> {
         Ok(Some(Message::Response {
-            id: id.to_string(),
-            response,
+            id: id.to_vec(),
+            values: response.to_values(),
             client: Some(self.identifier.clone())
         }))
     }
 
-    fn protocol_error (&self, id: &String) -> Result<Option<Message>, InvalidHashIdError> {
-        Ok(Some(Message::Error {
-            id: id.to_string(),
+    fn generic_error (&self, id: &[u8]) -> Option<Message> {
+        self.error(id, 201, "Generic Error")
+    }
+
+    fn protocol_error (&self, id: &[u8]) -> Option<Message> {
+        self.error(id, 203, "Protocol Error, such as a malformed packet, invalid arguments, or bad token")
+    }
+
+    fn error (&self, id: &[u8], code: u8, message: &str) -> Option<Message> {
+        Some(Message::Error {
+            id: id.to_vec(),
             client: Some(self.identifier.clone()),
-            error: ErrorResponse::new(203, "Protocol Error, such as a malformed packet, invalid arguments, or bad token".to_string())
-        }))
+            error: ErrorResponse::new(code, message.to_string())
+        })
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::structs::bucket::Bucket;
+    use crate::structs::clock::ManualClock;
 
     fn setup () -> DhtHandler {
         DhtHandler::new(
@@ -192,13 +700,802 @@ mod tests {
         )
     }
 
+    fn setup_with_clock() -> (DhtHandler, ManualClock) {
+        let clock = ManualClock::new();
+        let dht = DhtHandler::with_clock(
+            Node::new(Endpoint::new("127.0.0.1", 4444).unwrap(), HashId::new([17; 20])),
+            clock.clone(),
+        );
+
+        (dht, clock)
+    }
+
     #[test]
     fn test_responde_to_ping() {
         let mut dht = setup();
 
-        let response = dht.handle_str("d1:ad2:id40:ffffffffffffffffffffffffffffffffffffffffe1:t2:aa1:v4:aa001:y1:qe"
-            .to_string());
+        let response = dht.handle_datagram(
+            &[b"d1:ad2:id20:".as_ref(), &[255; 20], b"e1:q4:ping1:t2:aa1:v4:aa001:y1:qe"].concat(),
+            "127.0.0.1:4444".parse().unwrap(),
+        );
+
+        assert_eq!(
+            response.unwrap(),
+            [b"d1:rd2:id20:".as_ref(), &[17; 20], b"e1:t2:aa1:v4:MW011:y1:re"].concat()
+        );
+    }
+
+    fn query(args: Query) -> Vec<u8> {
+        Message::Query { id: b"aa".to_vec(), client: None, args }.to_bytes().unwrap()
+    }
+
+    fn ping(id: u8) -> Vec<u8> {
+        query(Query::Ping { id: HashId::new([id; 20]) })
+    }
+
+    fn get_peers(dht: &mut DhtHandler, source: SocketAddr) -> Response {
+        let args = Query::GetPeers { id: HashId::new([255; 20]), info_hash: HashId::new([34; 20]) };
+        let response = dht.handle_datagram(&query(args), source);
+
+        match Message::from_bytes(&response.unwrap()).unwrap() {
+            Message::Response { values, .. } => Response::from_values("get_peers", values).unwrap(),
+            _ => panic!("wrong response"),
+        }
+    }
 
-        assert_eq!(response.unwrap(), "d1:rd2:id40:1111111111111111111111111111111111111111e1:t2:aa1:v4:MW011:y1:re");
+    fn get_token(dht: &mut DhtHandler, source: SocketAddr) -> Token {
+        match get_peers(dht, source) {
+            Response::FoundPeerNodes { token, .. } => token,
+            _ => panic!("wrong response"),
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_token_is_bound_to_sender() {
+        let mut dht = setup();
+        let token = get_token(&mut dht, "10.0.0.1:4444".parse().unwrap());
+
+        assert!(dht.signer.verify(&token, &Endpoint::new("10.0.0.1", 5555).unwrap()));
+        assert!(!dht.signer.verify(&token, &Endpoint::new("127.0.0.1", 4444).unwrap()));
+    }
+
+    fn announce(token: &[u8]) -> Vec<u8> {
+        query(Query::AnnouncePeer {
+            id: HashId::new([255; 20]),
+            implied_port: None,
+            port: 6881,
+            token: token.to_vec(),
+            info_hash: HashId::new([34; 20]),
+        })
+    }
+
+    #[test]
+    fn test_tokens_expire_after_two_rotations() {
+        let (mut dht, clock) = setup_with_clock();
+        let source = "10.0.0.1:4444".parse().unwrap();
+        let token = get_token(&mut dht, source);
+
+        clock.advance(TokenAuthority::ROTATION_INTERVAL);
+        dht.tick();
+        assert!(dht.signer.verify(&token, &Endpoint::new("10.0.0.1", 4444).unwrap()));
+
+        clock.advance(TokenAuthority::ROTATION_INTERVAL);
+        dht.tick();
+
+        let rejected = dht.handle_datagram(&announce(&token), source);
+        assert!(matches!(Message::from_bytes(&rejected.unwrap()).unwrap(), Message::Error { .. }));
+    }
+
+    #[test]
+    fn test_announce_requires_valid_token() {
+        let mut dht = setup();
+        let token = get_token(&mut dht, "10.0.0.1:4444".parse().unwrap());
+
+        let rejected = dht.handle_datagram(&announce(&token), "10.0.0.2:4444".parse().unwrap());
+        match Message::from_bytes(&rejected.unwrap()).unwrap() {
+            Message::Error { .. } => {}
+            _ => panic!("announce from another address was accepted"),
+        }
+
+        let accepted = dht.handle_datagram(&announce(&token), "10.0.0.1:4444".parse().unwrap());
+        match Message::from_bytes(&accepted.unwrap()).unwrap() {
+            Message::Response { .. } => {}
+            _ => panic!("announce from the token owner was rejected"),
+        }
+
+        assert_eq!(
+            dht.peers.get(&HashId::new([34; 20]), dht.clock.now()),
+            vec![Endpoint::new("10.0.0.1", 6881).unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_store_announced_peers_until_they_expire() {
+        let (mut dht, clock) = setup_with_clock();
+        let source = "10.0.0.1:4444".parse().unwrap();
+        let token = get_token(&mut dht, source);
+
+        // announcing again stores the announced port only once
+        dht.handle_datagram(&announce(&token), source);
+        dht.handle_datagram(&announce(&token), source);
+
+        match get_peers(&mut dht, "10.0.0.2:4444".parse().unwrap()) {
+            Response::FoundPeers { values, .. } => assert_eq!(values, vec![Endpoint::new("10.0.0.1", 6881).unwrap()]),
+            _ => panic!("wrong response"),
+        }
+
+        clock.advance(PeerStore::TTL);
+        dht.tick();
+
+        assert!(dht.peers.is_empty());
+
+        // without peers the closest nodes are handed out again
+        get_token(&mut dht, "10.0.0.2:4444".parse().unwrap());
+    }
+
+    #[test]
+    fn test_reply_method_unknown() {
+        let mut dht = setup();
+
+        let response = dht.handle_datagram(
+            &[b"d1:ad2:id20:".as_ref(), &[255; 20], b"e1:q3:get1:t2:xy1:y1:qe"].concat(),
+            "127.0.0.1:4444".parse().unwrap(),
+        );
+
+        assert_eq!(
+            response.unwrap(),
+            b"d1:eli204e14:Method Unknowne1:t2:xy1:v4:MW011:y1:ee".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_reply_method_unknown_despite_mistyped_arguments() {
+        let mut dht = setup();
+        let queries = [
+            [b"d1:ad2:id20:".as_ref(), &[255; 20], b"6:target32:", &[1; 32], b"e1:q3:get1:t2:xy1:y1:qe"].concat(),
+            [b"d1:ad2:id20:".as_ref(), &[255; 20], b"4:porti70000ee1:q3:foo1:t2:xy1:y1:qe"].concat(),
+        ];
+
+        for query in queries.iter() {
+            let response = dht.handle_datagram(query, "127.0.0.1:4444".parse().unwrap());
+
+            assert_eq!(
+                response.unwrap(),
+                b"d1:eli204e14:Method Unknowne1:t2:xy1:v4:MW011:y1:ee".to_vec()
+            );
+        }
+    }
+
+    #[test]
+    fn test_reply_protocol_error_to_invalid_id() {
+        let mut dht = setup();
+
+        let response = dht.handle_datagram(
+            b"d1:ad2:id3:abce1:q9:find_node1:t2:xy1:y1:qe",
+            "127.0.0.1:4444".parse().unwrap(),
+        );
+
+        match Message::from_bytes(&response.unwrap()).unwrap() {
+            Message::Error { id, error, .. } => {
+                assert_eq!(id, b"xy".to_vec());
+                assert_eq!(error.code(), 203);
+            }
+            _ => panic!("wrong reply"),
+        }
+    }
+
+    #[test]
+    fn test_drop_invalid_messages() {
+        let mut dht = setup();
+        let source = "127.0.0.1:4444".parse().unwrap();
+
+        assert!(dht.handle_datagram(b"", source).is_none());
+        assert!(dht.handle_datagram(b"garbage", source).is_none());
+        assert!(dht.handle_datagram(b"d1:t2:aa1:y1:re", source).is_none());
+        assert!(dht.handle_datagram(b"d1:eli201e5:errore1:t2:aa1:y1:ee", source).is_none());
+        assert!(dht
+            .handle_datagram(&[b"d1:rd2:id20:".as_ref(), &[255; 20], b"e1:t2:aa1:y1:re"].concat(), source)
+            .is_none());
+    }
+
+    #[test]
+    fn test_ignore_ipv6_sources() {
+        let mut dht = setup();
+
+        assert!(dht.handle_datagram(&ping(255), "[::1]:4444".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_learn_querying_nodes() {
+        let mut dht = setup();
+        dht.handle_datagram(&ping(255), "10.0.0.1:4444".parse().unwrap());
+
+        let known = dht.buckets.find_closest_nodes(&HashId::new([255; 20]));
+        assert_eq!(known.len(), 1);
+        assert_eq!(known[0].endpoint, Endpoint::new("10.0.0.1", 4444).unwrap());
+    }
+
+    fn pong(transaction: &[u8], id: [u8; 20]) -> Vec<u8> {
+        [
+            b"d1:rd2:id20:".as_ref(),
+            &id,
+            b"e1:t2:",
+            transaction,
+            b"1:y1:re",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_send_queries() {
+        let mut dht = setup();
+        let endpoint = Endpoint::new("10.0.0.1", 6881).unwrap();
+
+        let ping = dht.ping(endpoint).unwrap();
+        let find_node = dht.find_node(endpoint, HashId::new([3; 20])).unwrap();
+        assert_ne!(ping, find_node);
+
+        let (datagram, destination) = dht.poll_outgoing().unwrap();
+        assert_eq!(destination, "10.0.0.1:6881".parse().unwrap());
+
+        match Message::from_bytes(&datagram).unwrap() {
+            Message::Query { id, args, .. } => {
+                assert_eq!(id, ping);
+                assert_eq!(args, Query::Ping { id: HashId::new([17; 20]) });
+            }
+            _ => panic!("wrong query"),
+        }
+
+        match Message::from_bytes(&dht.poll_outgoing().unwrap().0).unwrap() {
+            Message::Query { args: Query::FindNode { target, .. }, .. } => {
+                assert_eq!(target, HashId::new([3; 20]));
+            }
+            _ => panic!("wrong query"),
+        }
+
+        assert!(dht.poll_outgoing().is_none());
+    }
+
+    #[test]
+    fn test_match_responses_to_queries() {
+        let mut dht = setup();
+        let endpoint = Endpoint::new("10.0.0.1", 6881).unwrap();
+        let transaction = dht.ping(endpoint).unwrap();
+
+        // answers from other endpoints or for other transactions are dropped
+        dht.handle_datagram(&pong(&transaction, [5; 20]), "10.0.0.2:6881".parse().unwrap());
+        dht.handle_datagram(&pong(b"zz", [5; 20]), endpoint.to_socket_addr());
+        assert!(dht.poll_event().is_none());
+        assert!(dht.buckets.is_empty());
+
+        dht.handle_datagram(&pong(&transaction, [5; 20]), endpoint.to_socket_addr());
+
+        match dht.poll_event().unwrap() {
+            Event::Response { transaction: id, query: Query::Ping { .. }, response, .. } => {
+                assert_eq!(id, transaction);
+                assert_eq!(response.id(), HashId::new([5; 20]));
+            }
+            _ => panic!("wrong event"),
+        }
+
+        let known = dht.buckets.find_closest_nodes(&HashId::new([5; 20]));
+        assert_eq!(known[0].state(dht.clock.now()), NodeState::Good);
+
+        // a second answer to the same transaction is ignored
+        dht.handle_datagram(&pong(&transaction, [5; 20]), endpoint.to_socket_addr());
+        assert!(dht.poll_event().is_none());
+    }
+
+    #[test]
+    fn test_report_errors_to_queries() {
+        let mut dht = setup();
+        let endpoint = Endpoint::new("10.0.0.1", 6881).unwrap();
+        let transaction = dht.ping(endpoint).unwrap();
+
+        let error = [b"d1:eli202e6:Servere1:t2:".as_ref(), &transaction, b"1:y1:ee"].concat();
+        dht.handle_datagram(&error, endpoint.to_socket_addr());
+
+        match dht.poll_event().unwrap() {
+            Event::Error { transaction: id, error, .. } => {
+                assert_eq!(id, transaction);
+                assert_eq!(error.code(), 202);
+            }
+            _ => panic!("wrong event"),
+        }
+    }
+
+    #[test]
+    fn test_time_out_unanswered_queries() {
+        let (mut dht, clock) = setup_with_clock();
+        let endpoint = Endpoint::new("10.0.0.1", 6881).unwrap();
+        dht.handle_datagram(&ping(5), endpoint.to_socket_addr());
+
+        let mut transactions = vec![dht.ping(endpoint).unwrap(), dht.ping(endpoint).unwrap()];
+
+        clock.advance(Transactions::TIMEOUT - Duration::from_secs(1));
+        dht.tick();
+        assert!(dht.poll_event().is_none());
+
+        clock.advance(Duration::from_secs(1));
+        dht.tick();
+
+        while let Some(event) = dht.poll_event() {
+            match event {
+                Event::Timeout { transaction, endpoint: timed_out, .. } => {
+                    assert_eq!(timed_out, endpoint);
+                    transactions.retain(|id| *id != transaction);
+                }
+                _ => panic!("wrong event"),
+            }
+        }
+
+        assert!(transactions.is_empty());
+
+        let node = dht.buckets.find_by_endpoint(&endpoint).unwrap();
+        assert_eq!(node.state(dht.clock.now()), NodeState::Bad);
+        assert!(dht.buckets.find_closest_nodes(&HashId::new([5; 20])).is_empty());
+    }
+
+    #[test]
+    fn test_ping_questionable_nodes_of_full_bucket() {
+        let mut dht = setup();
+
+        for n in 0..9u8 {
+            dht.handle_datagram(&ping(200 + n), format!("10.0.0.{}:6881", n).parse().unwrap());
+        }
+
+        // the nine nodes never answered us, so the full bucket asks all of its nodes to prove they are alive
+        let mut pinged = 0;
+        while let Some((datagram, _)) = dht.poll_outgoing() {
+            match Message::from_bytes(&datagram).unwrap() {
+                Message::Query { args: Query::Ping { .. }, .. } => pinged += 1,
+                _ => panic!("wrong query"),
+            }
+        }
+
+        assert_eq!(pinged, 8);
+        assert_eq!(dht.transactions.len(), 8);
+    }
+
+    #[test]
+    fn test_type_responses_by_query() {
+        let mut dht = setup();
+        let endpoint = Endpoint::new("10.0.0.1", 6881).unwrap();
+        let transaction = dht.find_node(endpoint, HashId::new([3; 20])).unwrap();
+
+        // a bare pong doesn't answer a find_node query
+        dht.handle_datagram(&pong(&transaction, [5; 20]), endpoint.to_socket_addr());
+        assert!(dht.poll_event().is_none());
+        assert_eq!(dht.transactions.len(), 1);
+
+        let nodes = [
+            b"d1:rd2:id20:".as_ref(),
+            &[5; 20],
+            b"5:nodes0:e1:t2:",
+            &transaction,
+            b"1:y1:re",
+        ]
+        .concat();
+        dht.handle_datagram(&nodes, endpoint.to_socket_addr());
+
+        match dht.poll_event().unwrap() {
+            Event::Response { response, .. } => {
+                assert_eq!(response, Response::FoundNodes { id: HashId::new([5; 20]), nodes: CompactNodeList::default() });
+            }
+            _ => panic!("wrong event"),
+        }
+        assert!(dht.transactions.is_empty());
+    }
+
+    #[test]
+    fn test_learn_nodes_from_responses() {
+        let mut dht = setup();
+        let endpoint = Endpoint::new("10.0.0.1", 6881).unwrap();
+        let transaction = dht.get_peers(endpoint, HashId::new([3; 20])).unwrap();
+
+        let nodes = [1u8, 2, 17]
+            .iter()
+            .map(|n| Node::new(Endpoint::new("10.0.1.1", 6000 + *n as u16).unwrap(), HashId::new([*n; 20])))
+            .collect::<Vec<Node>>();
+        let nodes = CompactNodeList::new(nodes).to_bytes();
+
+        let response = [
+            b"d1:rd2:id20:".as_ref(),
+            &[5; 20],
+            b"5:nodes78:",
+            &nodes,
+            b"5:token2:xye1:t2:",
+            &transaction,
+            b"1:y1:re",
+        ]
+        .concat();
+        dht.handle_datagram(&response, endpoint.to_socket_addr());
+
+        // the responder is good, the nodes it told us about are not verified yet and our own id is skipped
+        assert_eq!(dht.buckets.len(), 3);
+        assert_eq!(dht.buckets.find_by_endpoint(&endpoint).unwrap().state(dht.clock.now()), NodeState::Good);
+
+        let learned = dht.buckets.find_by_endpoint(&Endpoint::new("10.0.1.1", 6002).unwrap()).unwrap();
+        assert_eq!(learned.node_id, HashId::new([2; 20]));
+        assert_eq!(learned.state(dht.clock.now()), NodeState::Questionable);
+    }
+
+    fn answer_find_node(dht: &mut DhtHandler, id: [u8; 20], nodes: Vec<Node>) -> Endpoint {
+        let (datagram, destination) = dht.poll_outgoing().unwrap();
+        let transaction = match Message::from_bytes(&datagram).unwrap() {
+            Message::Query { id, args: Query::FindNode { target, .. }, .. } => {
+                assert_eq!(target, HashId::new([17; 20]));
+                id
+            }
+            _ => panic!("wrong query"),
+        };
+
+        let nodes = CompactNodeList::new(nodes).to_bytes();
+        let response = [
+            b"d1:rd2:id20:".as_ref(),
+            &id,
+            format!("5:nodes{}:", nodes.len()).as_bytes(),
+            &nodes,
+            b"e1:t2:",
+            &transaction,
+            b"1:y1:re",
+        ]
+        .concat();
+        dht.handle_datagram(&response, destination);
+
+        Endpoint::from_socket_addr(destination).unwrap()
+    }
+
+    fn bootstrap_event(dht: &mut DhtHandler) -> Option<Event> {
+        std::iter::from_fn(|| dht.poll_event())
+            .find(|event| matches!(event, Event::Bootstrapped { .. } | Event::BootstrapFailed))
+    }
+
+    #[test]
+    fn test_bootstrap_until_table_stops_growing() {
+        let (mut dht, clock) = setup_with_clock();
+        let seed = Endpoint::new("10.0.0.1", 6881).unwrap();
+        let found = vec![
+            Node::new(Endpoint::new("10.0.1.1", 6881).unwrap(), HashId::new([16; 20])),
+            Node::new(Endpoint::new("10.0.1.2", 6881).unwrap(), HashId::new([18; 20])),
+        ];
+
+        dht.bootstrap(&[seed]);
+        assert_eq!(answer_find_node(&mut dht, [1; 20], found), seed);
+        assert!(bootstrap_event(&mut dht).is_none());
+
+        // the second round asks the nodes the seed told us about, one answers and one times out
+        answer_find_node(&mut dht, [16; 20], vec![]);
+        assert!(dht.poll_outgoing().is_some());
+        assert!(bootstrap_event(&mut dht).is_none());
+
+        clock.advance(Transactions::TIMEOUT);
+        dht.tick();
+
+        match bootstrap_event(&mut dht).unwrap() {
+            Event::Bootstrapped { nodes } => assert_eq!(nodes, 3),
+            _ => panic!("bootstrap failed"),
+        }
+        assert!(dht.poll_outgoing().is_none());
+    }
+
+    #[test]
+    fn test_bootstrap_fails_without_answers() {
+        let (mut dht, clock) = setup_with_clock();
+
+        dht.bootstrap(&[]);
+        assert!(matches!(bootstrap_event(&mut dht), Some(Event::BootstrapFailed)));
+
+        dht.bootstrap(&[Endpoint::new("10.0.0.1", 6881).unwrap()]);
+        clock.advance(Transactions::TIMEOUT);
+        dht.tick();
+        assert!(matches!(bootstrap_event(&mut dht), Some(Event::BootstrapFailed)));
+    }
+
+    fn node(id: u8) -> Node {
+        Node::new(Endpoint::new("10.0.1.1", 6000 + id as u16).unwrap(), HashId::new([id; 20]))
+    }
+
+    /// A handler that learned the given nodes from their pings.
+    fn seeded_handler(ids: &[u8]) -> DhtHandler {
+        let mut dht = setup();
+
+        for id in ids {
+            dht.handle_datagram(&ping(*id), node(*id).endpoint.to_socket_addr());
+        }
+
+        dht
+    }
+
+    #[test]
+    fn test_iterative_find_node_lookup() {
+        let mut dht = seeded_handler(&[64, 65]);
+        let target = HashId::new([0; 20]);
+
+        let lookup = dht.find_node_lookup(target);
+
+        // both known nodes point us to closer ones, which know nothing closer
+        let mut answered = vec![];
+        while let Some((datagram, destination)) = dht.poll_outgoing() {
+            let transaction = match Message::from_bytes(&datagram).unwrap() {
+                Message::Query { id, args: Query::FindNode { target: asked, .. }, .. } => {
+                    assert_eq!(asked, target);
+                    id
+                }
+                _ => panic!("wrong query"),
+            };
+
+            let endpoint = Endpoint::from_socket_addr(destination).unwrap();
+            let id = (endpoint.port - 6000) as u8;
+            let closer = match id {
+                64 => vec![node(1), node(2), node(17)],
+                65 => vec![node(3)],
+                _ => vec![],
+            };
+            let nodes = CompactNodeList::new(closer).to_bytes();
+
+            let response = [
+                b"d1:rd2:id20:".as_ref(),
+                &[id; 20],
+                format!("5:nodes{}:", nodes.len()).as_bytes(),
+                &nodes,
+                b"e1:t2:",
+                &transaction,
+                b"1:y1:re",
+            ]
+            .concat();
+            dht.handle_datagram(&response, destination);
+            answered.push(id);
+        }
+
+        // our own id is never queried
+        assert_eq!(answered.len(), 5);
+        assert!(!answered.contains(&17));
+
+        let finished = std::iter::from_fn(|| dht.poll_event())
+            .find_map(|event| match event {
+                Event::LookupFinished { lookup: id, nodes, .. } => Some((id, nodes)),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(finished.0, lookup);
+        assert_eq!(
+            finished.1.iter().map(|node| node.node_id.hash[0]).collect::<Vec<u8>>(),
+            vec![1, 2, 3, 64, 65]
+        );
+    }
+
+    #[test]
+    fn test_get_peers_lookup_reports_peers_incrementally() {
+        let mut dht = seeded_handler(&[64, 65]);
+        let info_hash = HashId::new([0; 20]);
+        let peer = |port: u16| Endpoint::new("10.0.2.1", port).unwrap();
+
+        let lookup = dht.get_peers_lookup(info_hash);
+        let mut found = vec![];
+
+        while let Some((datagram, destination)) = dht.poll_outgoing() {
+            let transaction = match Message::from_bytes(&datagram).unwrap() {
+                Message::Query { id, args: Query::GetPeers { info_hash: asked, .. }, .. } => {
+                    assert_eq!(asked, info_hash);
+                    id
+                }
+                _ => panic!("wrong query"),
+            };
+
+            let id = (Endpoint::from_socket_addr(destination).unwrap().port - 6000) as u8;
+            let response = match id {
+                64 => Response::FoundPeers { id: HashId::new([id; 20]), token: vec![id], values: vec![peer(1), peer(2)], nodes: None },
+                65 => Response::FoundPeerNodes { id: HashId::new([id; 20]), token: vec![id], nodes: vec![node(3)].into() },
+                _ => Response::FoundPeers { id: HashId::new([id; 20]), token: vec![id], values: vec![peer(2), peer(3)], nodes: None },
+            };
+            let message = Message::Response { id: transaction, client: None, values: response.to_values() };
+            dht.handle_datagram(&message.to_bytes().unwrap(), destination);
+
+            // peers are reported as soon as they arrive
+            while let Some(event) = dht.poll_event() {
+                match event {
+                    Event::PeersFound { lookup: id, peers, .. } => {
+                        assert_eq!(id, lookup);
+                        found.push(peers);
+                    }
+                    Event::LookupFinished { nodes, .. } => assert_eq!(nodes.len(), 3),
+                    _ => {}
+                }
+            }
+        }
+
+        assert_eq!(found, vec![vec![peer(1), peer(2)], vec![peer(3)]]);
+        assert!(dht.lookups.is_empty());
+    }
+
+    #[test]
+    fn test_get_peers_lookup_follows_nodes_sent_with_peers() {
+        let mut dht = seeded_handler(&[64]);
+        let lookup = dht.get_peers_lookup(HashId::new([0; 20]));
+        let mut asked = vec![];
+
+        while let Some((datagram, destination)) = dht.poll_outgoing() {
+            let transaction = match Message::from_bytes(&datagram).unwrap() {
+                Message::Query { id, args: Query::GetPeers { .. }, .. } => id,
+                _ => panic!("wrong query"),
+            };
+
+            let id = (Endpoint::from_socket_addr(destination).unwrap().port - 6000) as u8;
+            let nodes = if id == 64 { vec![node(1)] } else { vec![] };
+            let response = Response::FoundPeers {
+                id: HashId::new([id; 20]),
+                token: vec![id],
+                values: vec![Endpoint::new("10.0.2.1", 6881).unwrap()],
+                nodes: Some(nodes.into()),
+            };
+            let message = Message::Response { id: transaction, client: None, values: response.to_values() };
+            dht.handle_datagram(&message.to_bytes().unwrap(), destination);
+            asked.push(id);
+        }
+
+        // the swarm member still points us to a closer node, which we learn and ask as well
+        assert_eq!(asked, vec![64, 1]);
+        assert!(dht.buckets.find_by_endpoint(&node(1).endpoint).is_some());
+
+        let finished = std::iter::from_fn(|| dht.poll_event())
+            .find_map(|event| match event {
+                Event::LookupFinished { lookup: id, nodes, .. } if id == lookup => Some(nodes),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(finished, vec![node(1), node(64)]);
+    }
+
+    #[test]
+    fn test_announce_to_closest_responders() {
+        let mut dht = seeded_handler(&[64, 65]);
+        let info_hash = HashId::new([0; 20]);
+
+        let lookup = dht.announce(info_hash, 7000, false);
+        let mut announced_to = vec![];
+
+        while let Some((datagram, destination)) = dht.poll_outgoing() {
+            let id = (Endpoint::from_socket_addr(destination).unwrap().port - 6000) as u8;
+
+            let reply = match Message::from_bytes(&datagram).unwrap() {
+                Message::Query { id: transaction, args: Query::GetPeers { .. }, .. } => Message::Response {
+                    id: transaction,
+                    client: None,
+                    values: Response::FoundPeerNodes {
+                        id: HashId::new([id; 20]),
+                        token: vec![id],
+                        nodes: CompactNodeList::default(),
+                    }
+                    .to_values(),
+                },
+                Message::Query { id: transaction, args: Query::AnnouncePeer { port, token, info_hash: announced, .. }, .. } => {
+                    assert_eq!((port, token, announced), (7000, vec![id], info_hash));
+                    announced_to.push(id);
+
+                    // one node accepts the announce, the other one rejects it
+                    match id {
+                        64 => Message::Response {
+                            id: transaction,
+                            client: None,
+                            values: Response::Empty { id: HashId::new([id; 20]) }.to_values(),
+                        },
+                        _ => Message::Error {
+                            id: transaction,
+                            client: None,
+                            error: ErrorResponse::new(203, "bad token".to_string()),
+                        },
+                    }
+                }
+                _ => panic!("wrong query"),
+            };
+
+            dht.handle_datagram(&reply.to_bytes().unwrap(), destination);
+        }
+
+        assert_eq!(announced_to, vec![64, 65]);
+
+        let announced = std::iter::from_fn(|| dht.poll_event())
+            .find_map(|event| match event {
+                Event::Announced { lookup: id, accepted, .. } => Some((id, accepted)),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(announced, (lookup, 1));
+        assert!(dht.announces.is_empty());
+    }
+
+    #[test]
+    fn test_report_queries_that_cant_be_sent() {
+        let mut dht = seeded_handler(&[64]);
+
+        while dht.transactions.len() < Transactions::MAX_PENDING {
+            dht.ping(node(1).endpoint).unwrap();
+        }
+
+        let lookup = dht.find_node_lookup(HashId::new([0; 20]));
+        let events = std::iter::from_fn(|| dht.poll_event()).collect::<Vec<Event>>();
+
+        match &events[..] {
+            [Event::SendFailed { endpoint, error: DhtError::TooManyTransactions }, Event::LookupFinished { lookup: id, nodes, .. }] => {
+                assert_eq!(*endpoint, node(64).endpoint);
+                assert_eq!((*id, nodes.len()), (lookup, 0));
+            }
+            _ => panic!("wrong events {:?}", events),
+        }
+    }
+
+    #[test]
+    fn test_announce_without_nodes() {
+        let mut dht = setup();
+        let lookup = dht.announce(HashId::new([0; 20]), 7000, true);
+
+        assert!(dht.poll_outgoing().is_none());
+        assert!(std::iter::from_fn(|| dht.poll_event())
+            .any(|event| matches!(event, Event::Announced { lookup: id, accepted: 0, .. } if id == lookup)));
+    }
+
+    #[test]
+    fn test_reannounce_tracked_hashes() {
+        let (mut dht, clock) = setup_with_clock();
+        let info_hash = HashId::new([0; 20]);
+        let announced = |dht: &mut DhtHandler| {
+            std::iter::from_fn(|| dht.poll_event()).filter(|event| matches!(event, Event::Announced { .. })).count()
+        };
+
+        dht.track(info_hash, 7000, false);
+        assert!(dht.announce_status(&info_hash).unwrap().last_announce.is_none());
+
+        dht.tick();
+        assert_eq!(announced(&mut dht), 1);
+
+        let status = dht.announce_status(&info_hash).unwrap();
+        assert_eq!((status.last_announce, status.nodes_reached), (Some(clock.now()), 0));
+
+        clock.advance(Duration::from_secs(29 * 60));
+        dht.tick();
+        assert_eq!(announced(&mut dht), 0);
+
+        clock.advance(Duration::from_secs(60));
+        dht.tick();
+        assert_eq!(announced(&mut dht), 1);
+
+        assert!(dht.untrack(&info_hash));
+        clock.advance(Duration::from_secs(120 * 60));
+        dht.tick();
+        assert_eq!(announced(&mut dht), 0);
+    }
+
+    #[test]
+    fn test_refresh_stale_buckets() {
+        let (mut dht, clock) = setup_with_clock();
+
+        for n in 1..=4u8 {
+            dht.handle_datagram(&ping(n), format!("10.0.0.{}:6881", n).parse().unwrap());
+        }
+
+        dht.tick();
+        assert!(dht.poll_outgoing().is_none());
+
+        clock.advance(Bucket::REFRESH_INTERVAL);
+        dht.tick();
+
+        // the nodes never answered us, the lookup asks three of them and the last one is pinged
+        let mut queries = vec![];
+        let mut destinations = std::collections::HashSet::new();
+        while let Some((datagram, destination)) = dht.poll_outgoing() {
+            destinations.insert(destination);
+
+            match Message::from_bytes(&datagram).unwrap() {
+                Message::Query { args, .. } => queries.push(args.method()),
+                _ => panic!("wrong message"),
+            }
+        }
+
+        assert_eq!(queries, vec!["find_node", "find_node", "find_node", "ping"]);
+        assert_eq!(destinations.len(), 4);
+    }
+}
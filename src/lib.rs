@@ -0,0 +1,3 @@
+pub mod handler;
+pub mod server;
+pub mod structs;
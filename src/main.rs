@@ -1,26 +1,80 @@
-mod handler;
-mod structs;
+use std::env;
+use std::process;
 
-use handler::DhtHandler;
-use structs::node::*;
-use structs::util::*;
+use bittorent::server::Server;
+use bittorent::structs::bootstrap::{self, MAINLINE_SEEDS};
+use bittorent::structs::node::*;
+use bittorent::structs::util::*;
 
-fn main() {
-    let parsed =
-        Node::from_str("38636177a357835555a2be8b36b6a2c80bd2bd536a9d70e3b1d3".to_string()).unwrap();
-    println!("{:?}", parsed);
+const DEFAULT_PORT: u16 = 6881;
+
+struct Options {
+    port: u16,
+    seeds: Vec<String>,
+}
 
-    let endpoint1 = Endpoint::new("127.0.0.1", 4444).unwrap();
-    let endpoint2 = Endpoint::new("127.0.0.1", 5555).unwrap();
+fn parse_options() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut options = Options {
+        port: DEFAULT_PORT,
+        seeds: Vec::new(),
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" | "--port" => match args.next() {
+                Some(port) => options.port = port.parse().map_err(|_| format!("Invalid port: {}", port))?,
+                None => return Err("Missing value for --port".to_string()),
+            },
+            "-b" | "--bootstrap" => match args.next() {
+                Some(seed) => options.seeds.push(seed),
+                None => return Err("Missing value for --bootstrap".to_string()),
+            },
+            arg => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn resolve_seeds(seeds: &[String]) -> Vec<Endpoint> {
+    let seeds = seeds.iter().flat_map(|seed| match seed.as_str() {
+        "mainline" => MAINLINE_SEEDS.to_vec(),
+        seed => vec![seed],
+    });
+
+    seeds
+        .flat_map(|seed| {
+            bootstrap::resolve(seed).unwrap_or_else(|e| {
+                eprintln!("Can't resolve {}: {}", seed, e);
+                Vec::new()
+            })
+        })
+        .collect()
+}
+
+fn main() {
+    let options = parse_options().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        eprintln!("Usage: bittorent [--port <port>] [--bootstrap <host:port|mainline>]...");
+        process::exit(2);
+    });
+    let port = options.port;
 
-    let id1 = HashId::new([17; 20]);
-    let id2 = HashId::new([255; 20]);
+    let node = Node::new(Endpoint::new("0.0.0.0", port).unwrap(), HashId::random());
+    let mut server = Server::bind(port, node).unwrap_or_else(|e| {
+        eprintln!("Can't bind to port {}: {}", port, e);
+        process::exit(1);
+    });
 
-    let node1 = Node::new(endpoint1, id1);
-    let node2 = Node::new(endpoint2, id2);
+    println!("Listening on {} as {}", server.local_addr().unwrap(), node.node_id);
 
-    println!("{}", node1);
-    println!("{}", node2);
+    if !options.seeds.is_empty() {
+        server.handler().bootstrap(&resolve_seeds(&options.seeds));
+    }
 
-    println!("{}", node1.distance(node2));
+    if let Err(e) = server.run() {
+        eprintln!("Socket error: {}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,92 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::handler::{DhtHandler, Event};
+use crate::structs::node::*;
+
+pub struct Server {
+    socket: UdpSocket,
+    handler: DhtHandler,
+}
+
+impl Server {
+    const MAX_DATAGRAM_SIZE: usize = 65535;
+    const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn bind(port: u16, node: Node) -> io::Result<Server> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        socket.set_read_timeout(Some(Server::TICK_INTERVAL))?;
+
+        Ok(Server {
+            socket,
+            handler: DhtHandler::new(node),
+        })
+    }
+
+    pub fn handler(&mut self) -> &mut DhtHandler {
+        &mut self.handler
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut buffer = [0; Server::MAX_DATAGRAM_SIZE];
+
+        let mut last_tick = Instant::now();
+
+        loop {
+            self.flush();
+            self.report_events();
+
+            // the read timeout keeps ticking an idle server, a busy one ticks between datagrams
+            if last_tick.elapsed() >= Server::TICK_INTERVAL {
+                self.handler.tick();
+                last_tick = Instant::now();
+                continue;
+            }
+
+            let (size, source) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            };
+
+            if let Some(reply) = self.handler.handle_datagram(&buffer[..size], source) {
+                if let Err(e) = self.socket.send_to(&reply, source) {
+                    println!("Can't send reply to {}: {}", source, e);
+                }
+            }
+        }
+    }
+
+    fn report_events(&mut self) {
+        while let Some(event) = self.handler.poll_event() {
+            match event {
+                Event::Bootstrapped { nodes } => println!("Bootstrapped with {} nodes", nodes),
+                Event::BootstrapFailed => println!("Bootstrap failed, no node answered"),
+                Event::PeersFound { info_hash, peers, .. } => {
+                    println!("Found {} peers for {}", peers.len(), info_hash)
+                }
+                Event::Announced { info_hash, accepted, .. } => {
+                    println!("Announced {} to {} nodes", info_hash, accepted)
+                }
+                Event::NodeReplaced { failed, replacement } => {
+                    println!("Replaced unresponsive node {} with {}", failed, replacement)
+                }
+                Event::SendFailed { endpoint, error } => println!("Can't query {}: {}", endpoint, error),
+                _ => {}
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        while let Some((datagram, destination)) = self.handler.poll_outgoing() {
+            if let Err(e) = self.socket.send_to(&datagram, destination) {
+                println!("Can't send query to {}: {}", destination, e);
+            }
+        }
+    }
+}
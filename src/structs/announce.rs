@@ -0,0 +1,207 @@
+use super::message::MessageId;
+use super::util::HashId;
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Announcing takes a `get_peers` lookup for the tokens, followed by `announce_peer` to the closest responders.
+#[derive(Debug)]
+pub struct Announce {
+    pub info_hash: HashId,
+    pub port: u16,
+    pub implied_port: bool,
+    pending: HashSet<MessageId>,
+    accepted: usize,
+}
+
+impl Announce {
+    pub fn new(info_hash: HashId, port: u16, implied_port: bool) -> Announce {
+        Announce {
+            info_hash,
+            port,
+            implied_port,
+            pending: HashSet::new(),
+            accepted: 0,
+        }
+    }
+
+    pub fn sent(&mut self, transaction: MessageId) {
+        self.pending.insert(transaction);
+    }
+
+    /// Returns true if the transaction belonged to this announce.
+    pub fn finish(&mut self, transaction: &[u8], accepted: bool) -> bool {
+        if !self.pending.remove(transaction) {
+            return false;
+        }
+
+        if accepted {
+            self.accepted += 1;
+        }
+
+        true
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn accepted(&self) -> usize {
+        self.accepted
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnnounceStatus {
+    pub port: u16,
+    pub implied_port: bool,
+    pub last_announce: Option<Instant>,
+    pub nodes_reached: usize,
+    running: bool,
+}
+
+/// The info hashes we keep announcing, remote nodes forget announces after a while.
+#[derive(Debug)]
+pub struct AnnounceSchedule {
+    interval: Duration,
+    tracked: HashMap<HashId, AnnounceStatus>,
+}
+
+impl Default for AnnounceSchedule {
+    fn default() -> AnnounceSchedule {
+        AnnounceSchedule::new()
+    }
+}
+
+impl AnnounceSchedule {
+    pub const INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+    pub fn new() -> AnnounceSchedule {
+        AnnounceSchedule {
+            interval: AnnounceSchedule::INTERVAL,
+            tracked: HashMap::new(),
+        }
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Tracking a hash again only updates its port, it keeps its schedule.
+    pub fn add(&mut self, info_hash: HashId, port: u16, implied_port: bool) {
+        let status = self.tracked.entry(info_hash).or_insert(AnnounceStatus {
+            port,
+            implied_port,
+            last_announce: None,
+            nodes_reached: 0,
+            running: false,
+        });
+
+        status.port = port;
+        status.implied_port = implied_port;
+    }
+
+    pub fn remove(&mut self, info_hash: &HashId) -> bool {
+        self.tracked.remove(info_hash).is_some()
+    }
+
+    pub fn status(&self, info_hash: &HashId) -> Option<&AnnounceStatus> {
+        self.tracked.get(info_hash)
+    }
+
+    /// The announces that have to be started now, they aren't due again until they finished.
+    pub fn due(&mut self, now: Instant) -> Vec<(HashId, u16, bool)> {
+        let interval = self.interval;
+
+        self.tracked
+            .iter_mut()
+            .filter(|(_, status)| !status.running)
+            .filter(|(_, status)| match status.last_announce {
+                Some(last) => now.saturating_duration_since(last) >= interval,
+                None => true,
+            })
+            .map(|(info_hash, status)| {
+                status.running = true;
+                (*info_hash, status.port, status.implied_port)
+            })
+            .collect()
+    }
+
+    pub fn finished(&mut self, info_hash: &HashId, nodes_reached: usize, now: Instant) {
+        if let Some(status) = self.tracked.get_mut(info_hash) {
+            status.last_announce = Some(now);
+            status.nodes_reached = nodes_reached;
+            status.running = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_accepted_announces() {
+        let mut announce = Announce::new(HashId::new([1; 20]), 6881, false);
+        announce.sent(b"aa".to_vec());
+        announce.sent(b"ab".to_vec());
+
+        assert!(!announce.finish(b"zz", true));
+        assert!(announce.finish(b"aa", true));
+        assert!(!announce.is_finished());
+        assert!(announce.finish(b"ab", false));
+
+        assert!(announce.is_finished());
+        assert_eq!(announce.accepted(), 1);
+    }
+
+    #[test]
+    fn test_schedule_announces() {
+        let mut schedule = AnnounceSchedule::new();
+        let info_hash = HashId::new([1; 20]);
+        let now = Instant::now();
+
+        schedule.add(info_hash, 6881, false);
+        assert_eq!(schedule.due(now), vec![(info_hash, 6881, false)]);
+        assert!(schedule.due(now).is_empty());
+
+        schedule.finished(&info_hash, 5, now);
+        let status = schedule.status(&info_hash).unwrap();
+        assert_eq!((status.last_announce, status.nodes_reached), (Some(now), 5));
+
+        assert!(schedule.due(now + Duration::from_secs(29 * 60)).is_empty());
+        assert_eq!(schedule.due(now + Duration::from_secs(30 * 60)).len(), 1);
+    }
+
+    #[test]
+    fn test_configure_interval() {
+        let mut schedule = AnnounceSchedule::new();
+        let info_hash = HashId::new([1; 20]);
+        let now = Instant::now();
+
+        schedule.set_interval(Duration::from_secs(5 * 60));
+        schedule.add(info_hash, 6881, false);
+        schedule.due(now);
+        schedule.finished(&info_hash, 0, now);
+
+        assert_eq!(schedule.due(now + Duration::from_secs(5 * 60)).len(), 1);
+    }
+
+    #[test]
+    fn test_remove_tracked_hash() {
+        let mut schedule = AnnounceSchedule::new();
+        let info_hash = HashId::new([1; 20]);
+
+        schedule.add(info_hash, 6881, false);
+        schedule.add(info_hash, 7000, true);
+        assert_eq!(schedule.status(&info_hash).unwrap().port, 7000);
+
+        assert!(schedule.remove(&info_hash));
+        assert!(!schedule.remove(&info_hash));
+        assert!(schedule.due(Instant::now()).is_empty());
+
+        // finishing an announce of a removed hash doesn't track it again
+        schedule.finished(&info_hash, 3, Instant::now());
+        assert!(schedule.status(&info_hash).is_none());
+    }
+}
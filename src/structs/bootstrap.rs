@@ -0,0 +1,127 @@
+use super::message::MessageId;
+use super::node::Endpoint;
+
+use std::collections::HashSet;
+use std::io;
+use std::net::ToSocketAddrs;
+
+pub const MAINLINE_SEEDS: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "router.utorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+];
+
+/// Resolves a host:port seed, only IPv4 addresses are usable.
+pub fn resolve(seed: &str) -> io::Result<Vec<Endpoint>> {
+    Ok(seed.to_socket_addrs()?.filter_map(Endpoint::from_socket_addr).collect())
+}
+
+/// Rounds of `find_node` queries for our own id, every round asks the closest nodes we know
+/// until a round doesn't grow the routing table anymore.
+#[derive(Debug)]
+pub struct Bootstrap {
+    round: usize,
+    table_size: usize,
+    queried: HashSet<Endpoint>,
+    pending: HashSet<MessageId>,
+}
+
+impl Bootstrap {
+    pub const MAX_ROUNDS: usize = 8;
+
+    pub fn new(table_size: usize) -> Bootstrap {
+        Bootstrap {
+            round: 0,
+            table_size,
+            queried: HashSet::new(),
+            pending: HashSet::new(),
+        }
+    }
+
+    /// Returns false for endpoints that were already asked during this bootstrap.
+    pub fn should_query(&mut self, endpoint: Endpoint) -> bool {
+        self.queried.insert(endpoint)
+    }
+
+    pub fn sent(&mut self, transaction: MessageId) {
+        self.pending.insert(transaction);
+    }
+
+    /// Returns true if the transaction belonged to this bootstrap.
+    pub fn finish(&mut self, transaction: &[u8]) -> bool {
+        self.pending.remove(transaction)
+    }
+
+    pub fn round_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn round(&self) -> usize {
+        self.round
+    }
+
+    /// Starts the next round if the last one improved the table.
+    pub fn next_round(&mut self, table_size: usize) -> bool {
+        let improved = table_size > self.table_size;
+
+        self.table_size = table_size;
+        self.round += 1;
+
+        improved && self.round < Bootstrap::MAX_ROUNDS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_endpoints_once() {
+        let mut bootstrap = Bootstrap::new(0);
+        let endpoint = Endpoint::new("10.0.0.1", 6881).unwrap();
+
+        assert!(bootstrap.should_query(endpoint));
+        assert!(!bootstrap.should_query(endpoint));
+    }
+
+    #[test]
+    fn test_round_completes_with_last_transaction() {
+        let mut bootstrap = Bootstrap::new(0);
+        bootstrap.sent(b"aa".to_vec());
+        bootstrap.sent(b"ab".to_vec());
+
+        assert!(!bootstrap.finish(b"zz"));
+        assert!(bootstrap.finish(b"aa"));
+        assert!(!bootstrap.round_complete());
+        assert!(bootstrap.finish(b"ab"));
+        assert!(bootstrap.round_complete());
+    }
+
+    #[test]
+    fn test_stop_when_table_stops_growing() {
+        let mut bootstrap = Bootstrap::new(0);
+
+        assert!(bootstrap.next_round(5));
+        assert!(bootstrap.next_round(12));
+        assert!(!bootstrap.next_round(12));
+        assert_eq!(bootstrap.round(), 3);
+    }
+
+    #[test]
+    fn test_stop_after_max_rounds() {
+        let mut bootstrap = Bootstrap::new(0);
+
+        for size in 1..Bootstrap::MAX_ROUNDS {
+            assert!(bootstrap.next_round(size));
+        }
+
+        assert!(!bootstrap.next_round(100));
+    }
+
+    #[test]
+    fn test_resolve_ip_seeds() {
+        assert_eq!(resolve("10.0.0.1:6881").unwrap(), vec![Endpoint::new("10.0.0.1", 6881).unwrap()]);
+        assert!(resolve("[::1]:6881").unwrap().is_empty());
+        assert!(resolve("no port").is_err());
+    }
+}
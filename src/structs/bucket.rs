@@ -1,189 +1,330 @@
+use super::clock::{Clock, SystemClock};
 use super::error::*;
 use super::node::*;
 use super::util::*;
 
-use chrono::{DateTime, Utc, Duration};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct Kbuckets {
+    own_id: HashId,
     buckets: Vec<Bucket>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Kbuckets {
-    pub fn new() -> Kbuckets {
+    pub fn new(own_id: HashId) -> Kbuckets {
+        Kbuckets::with_clock(own_id, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(own_id: HashId, clock: Arc<dyn Clock>) -> Kbuckets {
         Kbuckets {
-            buckets: vec![Bucket::new(HashId::new([255; 20]))],
+            own_id,
+            buckets: vec![Bucket::new(own_id, 0, clock.now())],
+            clock,
         }
     }
 
-    pub fn find_closest_nodes(&self, id: &HashId) -> Option<Vec<Node>> {
-        let (index, bucket) = self.find_index(id)?;
-        let mut closest = Vec::<Node>::new();
-        let mut offset = 0;
+    /// Finds the closest good nodes, questionable nodes are only used to fill up the answer.
+    pub fn find_closest_nodes(&self, id: &HashId) -> Vec<Node> {
+        let now = self.clock.now();
+        let mut closest =
+            self.find_closest_nodes_matching(id, Bucket::SIZE, |node| node.state(now) == NodeState::Good);
+
+        if closest.len() < Bucket::SIZE {
+            closest.extend(self.find_closest_nodes_matching(
+                id,
+                Bucket::SIZE - closest.len(),
+                |node| node.state(now) == NodeState::Questionable,
+            ));
+            closest.sort_unstable_by_key(|node| node.node_id ^ *id);
+        }
 
-        closest.extend_from_slice(&bucket.nodes);
+        closest
+    }
 
-        loop {
-            offset += 1;
-            let len = closest.len();
+    pub fn find_closest_nodes_matching<F>(&self, id: &HashId, count: usize, filter: F) -> Vec<Node>
+    where
+        F: Fn(&Node) -> bool,
+    {
+        // buckets cover disjoint prefixes, so the distances of their nodes to the target
+        // are disjoint ranges as well and the buckets can be visited closest first
+        let mut by_distance = self.buckets.iter().collect::<Vec<&Bucket>>();
+        by_distance.sort_unstable_by_key(|bucket| (bucket.prefix ^ *id).masked(bucket.prefix_len));
 
-            self.try_extend(&mut closest, index, offset);
+        let mut closest = Vec::<Node>::new();
 
-            if len == closest.len() || closest.len() == 8 {
+        for bucket in by_distance {
+            if closest.len() >= count {
                 break;
             }
+
+            closest.extend(bucket.nodes.iter().filter(|node| filter(node)));
         }
 
-        closest.sort_unstable_by(|a, b| (a.node_id ^ *id).cmp(&(b.node_id ^ *id)));
-        closest.truncate(8);
+        closest.sort_unstable_by_key(|node| node.node_id ^ *id);
+        closest.truncate(count);
 
-        Some(closest)
+        closest
     }
 
-    pub fn try_insert(&mut self, our_id: &HashId, new_node: Node) {
-        // find bucket, if not full -> insert
-        // if full -> find bucket for self
-        // if self bucket == new bucket, split bucket at self
-        // insert again
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.nodes.len()).sum()
     }
 
-    fn try_extend(&self, merged: &mut Vec<Node>, start: usize, offset: usize) {
-        let upper = start.checked_add(offset);
-        let lower = start.checked_sub(offset);
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        if upper.is_none() || lower.is_none() {
-            return;
-        }
+    pub fn try_insert(&mut self, new_node: Node) -> Result<Insertion, DhtError> {
+        let now = self.clock.now();
 
-        for i in [upper.unwrap(), lower.unwrap()].iter() {
-            match self.buckets.get(*i) {
-                Some(bucket) => merged.extend_from_slice(bucket.nodes.as_slice()),
-                None => continue,
-            };
-        }
-    }
+        loop {
+            let index = self.bucket_index(&new_node.node_id);
+            let is_own_bucket = index + 1 == self.buckets.len();
+            let bucket = &mut self.buckets[index];
 
-    pub fn find_index(&self, id: &HashId) -> Option<(usize, &Bucket)> {
-        for (i, bucket) in self.buckets.iter().enumerate() {
-            if id <= &bucket.upper_boundary {
-                return Some((i, bucket));
+            if bucket.find_mut(&new_node.node_id).is_some() {
+                return Ok(Insertion::Updated);
             }
+
+            if bucket.nodes.len() >= Bucket::SIZE {
+                bucket.remove_bad_node(now);
+            }
+
+            if bucket.nodes.len() < Bucket::SIZE {
+                bucket.insert(new_node, now)?;
+                return Ok(Insertion::Added);
+            }
+
+            // only the last bucket covers our own id and may be split
+            if !is_own_bucket || bucket.prefix_len == HashId::BITS {
+                bucket.cache(new_node);
+                return Ok(Insertion::Cached(
+                    bucket.questionables(now).into_iter().copied().collect(),
+                ));
+            }
+
+            self.split();
         }
+    }
 
-        None
+    pub fn evict(&mut self, id: &HashId) -> Option<Node> {
+        let now = self.clock.now();
+        self.find_mut(id).evict(id, now)
     }
 
-    pub fn find(&self, id: &HashId) -> Option<&Bucket> {
-        match self.find_index(id) {
-            Some((_, bucket)) => Some(bucket),
-            None => None,
-        }
+    pub fn bucket_index(&self, id: &HashId) -> usize {
+        self.own_id
+            .shared_prefix_len(id)
+            .min(self.buckets.len() - 1)
     }
 
-    pub fn find_index_mut(&mut self, id: HashId) -> Option<(usize, &mut Bucket)> {
-        for (i, bucket) in self.buckets.iter_mut().enumerate() {
-            if id <= bucket.upper_boundary {
-                return Some((i, bucket));
-            }
-        }
+    pub fn find(&self, id: &HashId) -> &Bucket {
+        &self.buckets[self.bucket_index(id)]
+    }
 
-        None
+    pub fn find_mut(&mut self, id: &HashId) -> &mut Bucket {
+        let index = self.bucket_index(id);
+        &mut self.buckets[index]
     }
 
-    pub fn find_mut(&mut self, id: HashId) -> Option<&mut Bucket> {
-        match self.find_index_mut(id) {
-            Some((_, bucket)) => Some(bucket),
-            None => None,
+    pub fn find_by_endpoint(&self, endpoint: &Endpoint) -> Option<&Node> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .find(|node| node.endpoint == *endpoint)
+    }
+
+    /// Picks a random target in every bucket that didn't change for a while, a lookup for it refreshes
+    /// the bucket. The questionable nodes of those buckets should be pinged as well.
+    pub fn refresh(&mut self) -> Vec<(HashId, Vec<Node>)> {
+        let now = self.clock.now();
+
+        self.buckets
+            .iter_mut()
+            .filter(|bucket| now.saturating_duration_since(bucket.last_changed) >= Bucket::REFRESH_INTERVAL)
+            .map(|bucket| {
+                bucket.last_changed = now;
+
+                (
+                    bucket.prefix.random_with_prefix(bucket.prefix_len),
+                    bucket.questionables(now).into_iter().copied().collect(),
+                )
+            })
+            .collect()
+    }
+
+    pub fn mark_responded(&mut self, id: &HashId) {
+        let now = self.clock.now();
+        self.find_mut(id).mark_responded(id, now);
+    }
+
+    pub fn mark_queried(&mut self, id: &HashId) {
+        let now = self.clock.now();
+
+        if let Some(node) = self.find_mut(id).find_mut(id) {
+            node.queried(now);
         }
     }
 
-    pub fn update_timestamps(&mut self, id: &HashId) {
-        match self.find_mut(*id) {
-            Some(bucket) => bucket.update_timestamps(id),
-            None => {}
+    /// Counts a query the node didn't answer, bad nodes are replaced if a replacement is known.
+    pub fn mark_failed(&mut self, id: &HashId) -> Option<Node> {
+        let now = self.clock.now();
+        let bucket = self.find_mut(id);
+        let node = bucket.find_mut(id)?;
+        node.failed();
+
+        if node.state(now) == NodeState::Bad && bucket.replacements().next().is_some() {
+            return bucket.evict(id, now);
         }
+
+        None
     }
 
-    pub fn split(&mut self, id: HashId) {
-        let mut new_bucket = Bucket::new(id);
-        let (index, bucket) = self.find_index_mut(id).unwrap();
+    fn split(&mut self) {
+        let depth = self.buckets.len() - 1;
+        let own = self.buckets.pop().unwrap();
+
+        let mut far = Bucket::new(self.own_id.flip_bit(depth), depth + 1, own.last_changed);
+        let mut near = Bucket::new(self.own_id, depth + 1, own.last_changed);
+
+        for node in own.nodes {
+            if far.contains(&node.node_id) {
+                far.nodes.push(node);
+            } else {
+                near.nodes.push(node);
+            }
+        }
 
-        let mut i = 0;
-        while i < bucket.nodes.len() {
-            if bucket.nodes[i].node_id <= new_bucket.upper_boundary {
-                let node = bucket.nodes.swap_remove(i);
-                new_bucket.nodes.push(node);
+        for node in own.replacements {
+            if far.contains(&node.node_id) {
+                far.replacements.push_back(node);
             } else {
-                i += 1;
+                near.replacements.push_back(node);
             }
         }
 
-        self.buckets.insert(index, new_bucket);
+        self.buckets.push(far);
+        self.buckets.push(near);
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum Insertion {
+    Added,
+    Updated,
+    /// The bucket is full and the node waits in its replacement cache. The questionable
+    /// nodes of the bucket should be pinged and evicted if they don't answer.
+    Cached(Vec<Node>),
+}
+
 #[derive(Debug)]
 pub struct Bucket {
-    pub upper_boundary: HashId,
+    pub prefix: HashId,
+    pub prefix_len: usize,
     pub nodes: Vec<Node>,
-    last_changed: DateTime<Utc>,
+    replacements: VecDeque<Node>,
+    last_changed: Instant,
 }
 
 impl Bucket {
-    const SIZE: usize = 8;
+    pub const SIZE: usize = 8;
+    pub const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
 
-    pub fn new(upper_boundary: HashId) -> Bucket {
+    pub fn new(prefix: HashId, prefix_len: usize, now: Instant) -> Bucket {
         Bucket {
-            upper_boundary,
+            prefix: prefix.masked(prefix_len),
+            prefix_len,
             nodes: Vec::new(),
-            last_changed: Utc::now(),
+            replacements: VecDeque::new(),
+            last_changed: now,
         }
     }
 
-    pub fn insert(&mut self, node: Node) -> Result<(), BucketError> {
+    pub fn last_changed(&self) -> Instant {
+        self.last_changed
+    }
+
+    pub fn contains(&self, id: &HashId) -> bool {
+        self.prefix.shared_prefix_len(id) >= self.prefix_len
+    }
+
+    pub fn lower_boundary(&self) -> HashId {
+        self.prefix
+    }
+
+    pub fn upper_boundary(&self) -> HashId {
+        self.prefix.filled(self.prefix_len)
+    }
+
+    pub fn insert(&mut self, node: Node, now: Instant) -> Result<(), DhtError> {
         if self.nodes.len() >= Bucket::SIZE {
-            return Err(BucketError::new("Bucket is already full".to_string()));
+            return Err(DhtError::BucketFull);
         }
 
-        if self.upper_boundary < node.node_id {
-            return Err(BucketError::new(
-                "NodeID is not within the buckets boundary".to_string(),
-            ));
+        if !self.contains(&node.node_id) {
+            return Err(DhtError::OutOfBucketRange);
         }
 
-        self.last_changed = Utc::now();
+        self.last_changed = now;
         self.nodes.push(node);
         Ok(())
     }
 
-    pub fn find_mut(&mut self, id: &HashId) -> Option<&mut Node> {
-        for node in self.nodes.iter_mut() {
-            if node.node_id == *id {
-                return Some(node);
-            }
+    /// Remembers a node that didn't fit into the bucket, the most recently seen is kept last.
+    pub fn cache(&mut self, node: Node) {
+        self.replacements.retain(|cached| cached.node_id != node.node_id);
+        self.replacements.push_back(node);
+
+        if self.replacements.len() > Bucket::SIZE {
+            self.replacements.pop_front();
         }
+    }
 
-        None
+    pub fn replacements(&self) -> impl Iterator<Item = &Node> {
+        self.replacements.iter()
     }
 
-    pub fn update_timestamps(&mut self, id: &HashId) {
-        match self.find_mut(id) {
-            Some(node) => {
-                node.last_seen = Utc::now();
-                self.last_changed = Utc::now();
-            }
-            None => {}
+    /// Removes a node and promotes the most recently seen replacement in its place.
+    pub fn evict(&mut self, id: &HashId, now: Instant) -> Option<Node> {
+        let index = self.nodes.iter().position(|node| node.node_id == *id)?;
+        self.nodes.swap_remove(index);
+
+        let promoted = self.replacements.pop_back()?;
+        self.nodes.push(promoted);
+        self.last_changed = now;
+
+        Some(promoted)
+    }
+
+    pub fn find_mut(&mut self, id: &HashId) -> Option<&mut Node> {
+        self.nodes.iter_mut().find(|node| node.node_id == *id)
+    }
+
+    pub fn mark_responded(&mut self, id: &HashId, now: Instant) {
+        if let Some(node) = self.find_mut(id) {
+            node.responded(now);
+            self.last_changed = now;
+        }
+    }
+
+    fn remove_bad_node(&mut self, now: Instant) {
+        if let Some(index) = self.nodes.iter().position(|node| node.state(now) == NodeState::Bad) {
+            self.nodes.swap_remove(index);
         }
     }
 
-    pub fn questionables(&self) -> Vec<&Node> {
+    pub fn questionables(&self, now: Instant) -> Vec<&Node> {
         let mut questionable = self
             .nodes
             .iter()
-            .filter(|node| node.questionable())
+            .filter(|node| node.questionable(now))
             .collect::<Vec<&Node>>();
 
-        questionable.sort_unstable_by(|a, b| a.last_seen.cmp(&b.last_seen));
+        questionable.sort_unstable_by_key(|node| node.last_seen);
         questionable
     }
 }
@@ -191,17 +332,39 @@ impl Bucket {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::structs::clock::ManualClock;
+
+    const MINUTE: Duration = Duration::from_secs(60);
 
     fn get_node(id: [u8; 20]) -> Node {
-        Node::new(Endpoint::new("127.0.0.1", 4444).unwrap(), HashId::new(id))
+        node_seen_at(id, Instant::now())
+    }
+
+    fn node_seen_at(id: [u8; 20], time: Instant) -> Node {
+        let mut node = Node::new(Endpoint::new("127.0.0.1", 4444).unwrap(), HashId::new(id));
+        node.responded(time);
+        node
+    }
+
+    fn table() -> (Kbuckets, ManualClock) {
+        let clock = ManualClock::new();
+        (Kbuckets::with_clock(HashId::new([0; 20]), Arc::new(clock.clone())), clock)
+    }
+
+    /// Lets the nodes answer without changing their buckets.
+    fn respond<I: IntoIterator<Item = u8>>(buckets: &mut Kbuckets, ids: I, now: Instant) {
+        for n in ids {
+            let id = HashId::new([n; 20]);
+            buckets.find_mut(&id).find_mut(&id).unwrap().responded(now);
+        }
     }
 
     #[test]
     fn test_can_insert_node() {
-        let mut bucket = Bucket::new(HashId::new([255; 20]));
+        let mut bucket = Bucket::new(HashId::new([0; 20]), 0, Instant::now());
         let node = get_node([0; 20]);
 
-        bucket.insert(node).unwrap();
+        bucket.insert(node, Instant::now()).unwrap();
 
         assert_eq!(bucket.nodes.len(), 1);
     }
@@ -209,135 +372,482 @@ mod tests {
     #[test]
     #[should_panic(expected = "Bucket is already full")]
     fn test_can_insert_correct_amount_of_nodes() {
-        let mut bucket = Bucket::new(HashId::new([255; 20]));
+        let mut bucket = Bucket::new(HashId::new([0; 20]), 0, Instant::now());
 
         for n in 0..8 {
             let node = get_node([n; 20]);
-            bucket.insert(node).unwrap();
+            bucket.insert(node, Instant::now()).unwrap();
         }
 
         assert_eq!(bucket.nodes.len(), 8);
 
         let node = get_node([10; 20]);
-        bucket.insert(node).unwrap();
+        bucket.insert(node, Instant::now()).unwrap();
     }
 
     #[test]
     #[should_panic(expected = "NodeID is not within the buckets boundary")]
     fn test_bucket_boundaries_are_checked() {
-        let mut bucket = Bucket::new(HashId::new([17; 20]));
+        let mut bucket = Bucket::new(HashId::new([17; 20]), 8, Instant::now());
         let node = get_node([18; 20]);
 
-        bucket.insert(node).unwrap();
+        bucket.insert(node, Instant::now()).unwrap();
     }
 
     #[test]
     fn test_last_changed_is_updated() {
-        let mut bucket = Bucket::new(HashId::new([17; 20]));
-        let node = get_node([1; 20]);
+        let now = Instant::now();
+        let mut bucket = Bucket::new(HashId::new([0; 20]), 0, now);
 
-        let before = bucket.last_changed;
-        bucket.insert(node).unwrap();
+        bucket.insert(get_node([1; 20]), now + MINUTE).unwrap();
 
-        assert!(before < bucket.last_changed);
+        assert_eq!(bucket.last_changed(), now + MINUTE);
     }
 
     #[test]
     fn test_list_questionables() {
-        let mut bucket = Bucket::new(HashId::new([255; 20]));
-        let mut old_node = get_node([3; 20]);
-        let mut older_node = get_node([2; 20]);
-        let node = get_node([1; 20]);
+        let start = Instant::now();
+        let now = start + 20 * MINUTE;
+        let mut bucket = Bucket::new(HashId::new([0; 20]), 0, start);
+        let old_node = node_seen_at([3; 20], start + 4 * MINUTE);
+        let older_node = node_seen_at([2; 20], start);
+        let node = node_seen_at([1; 20], now);
 
-        old_node.last_seen = old_node
-            .last_seen
-            .checked_sub_signed(Duration::minutes(16))
-            .unwrap();
-        older_node.last_seen = older_node
-            .last_seen
-            .checked_sub_signed(Duration::minutes(20))
-            .unwrap();
+        bucket.insert(node, now).unwrap();
+        bucket.insert(old_node, now).unwrap();
+        bucket.insert(older_node, now).unwrap();
 
-        bucket.insert(node).unwrap();
-        bucket.insert(old_node).unwrap();
-        bucket.insert(older_node).unwrap();
+        assert_eq!(bucket.questionables(now).len(), 2);
 
-        assert_eq!(bucket.questionables().len(), 2);
-
-        assert_eq!(*bucket.questionables()[0], older_node);
-        assert_eq!(*bucket.questionables()[1], old_node);
+        assert_eq!(*bucket.questionables(now)[0], older_node);
+        assert_eq!(*bucket.questionables(now)[1], old_node);
     }
 
     #[test]
     fn test_split_buckets() {
-        let mut buckets = Kbuckets::new();
+        let mut buckets = Kbuckets::new(HashId::new([0; 20]));
 
         assert_eq!(buckets.buckets.len(), 1);
-        assert_eq!(buckets.buckets[0].upper_boundary, HashId::new([255; 20]));
+        assert_eq!(buckets.buckets[0].prefix_len, 0);
 
-        buckets.split(HashId::new([150; 20]));
+        buckets.split();
 
         assert_eq!(buckets.buckets.len(), 2);
-        assert_eq!(buckets.buckets[0].upper_boundary, HashId::new([150; 20]));
-        assert_eq!(buckets.buckets[1].upper_boundary, HashId::new([255; 20]));
+        assert_eq!(buckets.buckets[0].lower_boundary(), HashId::new([0; 20]).flip_bit(0));
+        assert_eq!(buckets.buckets[0].upper_boundary(), HashId::new([255; 20]));
+        assert_eq!(buckets.buckets[1].lower_boundary(), HashId::new([0; 20]));
+        assert_eq!(buckets.buckets[1].upper_boundary(), HashId::new([255; 20]).flip_bit(0));
     }
 
     #[test]
     fn test_find_bucket() {
-        let mut buckets = Kbuckets::new();
-
-        let split_boundary = HashId::new([150; 20]);
+        let mut buckets = Kbuckets::new(HashId::new([0; 20]));
 
-        buckets.split(split_boundary);
+        buckets.split();
+        buckets.split();
 
-        assert_eq!(
-            buckets
-                .find_mut(HashId::new([10; 20]))
-                .unwrap()
-                .upper_boundary,
-            split_boundary
-        );
-        assert_eq!(
-            buckets
-                .find_mut(HashId::new([200; 20]))
-                .unwrap()
-                .upper_boundary,
-            HashId::new([0xFF; 20])
-        );
+        assert_eq!(buckets.find(&HashId::new([200; 20])).prefix_len, 1);
+        assert_eq!(buckets.find(&HashId::new([100; 20])).prefix_len, 2);
+        assert_eq!(buckets.find(&HashId::new([10; 20])).prefix_len, 2);
+        assert!(buckets.find(&HashId::new([10; 20])).contains(&HashId::new([0; 20])));
     }
 
     #[test]
     fn test_find_closest_nodes() {
-        let mut buckets = Kbuckets::new();
-
-        buckets.split(HashId::new([6; 20]));
-        buckets.split(HashId::new([4; 20]));
+        let mut buckets = Kbuckets::new(HashId::new([0; 20]));
 
-        for i in 0..13 {
+        for i in 1..13 {
             if i == 5 || i == 4 {
                 continue;
             }
 
-            let node = get_node([i; 20]);
-            buckets
-                .find_mut(node.node_id)
-                .unwrap()
-                .insert(node)
-                .unwrap();
+            buckets.try_insert(get_node([i; 20])).unwrap();
         }
 
-        let closest = buckets.find_closest_nodes(&HashId::new([5; 20])).unwrap();
+        assert!(buckets.buckets.len() > 1);
+
+        let closest = buckets.find_closest_nodes(&HashId::new([5; 20]));
 
         assert_eq!(closest.len(), 8);
         assert_eq!(closest[0].node_id, HashId::new([7; 20]));
         assert_eq!(closest[1].node_id, HashId::new([6; 20]));
         assert_eq!(closest[2].node_id, HashId::new([1; 20]));
-        assert_eq!(closest[3].node_id, HashId::new([0; 20]));
-        assert_eq!(closest[4].node_id, HashId::new([3; 20]));
-        assert_eq!(closest[5].node_id, HashId::new([2; 20]));
-        assert_eq!(closest[6].node_id, HashId::new([12; 20]));
-        assert_eq!(closest[7].node_id, HashId::new([9; 20]));
+        assert_eq!(closest[3].node_id, HashId::new([3; 20]));
+        assert_eq!(closest[4].node_id, HashId::new([2; 20]));
+        assert_eq!(closest[5].node_id, HashId::new([12; 20]));
+        assert_eq!(closest[6].node_id, HashId::new([9; 20]));
+        assert_eq!(closest[7].node_id, HashId::new([8; 20]));
+    }
+
+    #[test]
+    fn test_insert_into_table() {
+        let mut buckets = Kbuckets::new(HashId::new([0; 20]));
+
+        buckets.try_insert(get_node([200; 20])).unwrap();
+        buckets.try_insert(get_node([200; 20])).unwrap();
 
-        println!("{:?}", closest);
+        assert_eq!(buckets.buckets.len(), 1);
+        assert_eq!(buckets.buckets[0].nodes.len(), 1);
+    }
+
+    #[test]
+    fn test_split_bucket_covering_our_id() {
+        let mut buckets = Kbuckets::new(HashId::new([0; 20]));
+
+        for n in 0..8 {
+            buckets.try_insert(get_node([128 + n; 20])).unwrap();
+        }
+        assert_eq!(buckets.buckets.len(), 1);
+
+        buckets.try_insert(get_node([1; 20])).unwrap();
+
+        assert_eq!(buckets.buckets.len(), 2);
+        assert_eq!(buckets.buckets[0].nodes.len(), 8);
+        assert_eq!(buckets.buckets[1].nodes.len(), 1);
+    }
+
+    #[test]
+    fn test_reject_node_for_full_foreign_bucket() {
+        let mut buckets = Kbuckets::new(HashId::new([0; 20]));
+
+        for n in 0..8 {
+            buckets.try_insert(get_node([128 + n; 20])).unwrap();
+        }
+        buckets.try_insert(get_node([1; 20])).unwrap();
+
+        assert_eq!(
+            buckets.try_insert(get_node([200; 20])).unwrap(),
+            Insertion::Cached(vec![])
+        );
+        assert_eq!(buckets.buckets.len(), 2);
+        assert_eq!(buckets.buckets[0].nodes.len(), 8);
+        assert_eq!(buckets.buckets[0].replacements().count(), 1);
+    }
+
+    #[test]
+    fn test_split_repeatedly_towards_our_id() {
+        let mut buckets = Kbuckets::new(HashId::new([0; 20]));
+
+        for n in 0..40 {
+            let mut id = [0; 20];
+            id[0] = n;
+            id[19] = 1;
+            let _ = buckets.try_insert(get_node(id));
+        }
+
+        let total: usize = buckets.buckets.iter().map(|bucket| bucket.nodes.len()).sum();
+        assert!(buckets.buckets.len() > 2);
+        assert!(total > 8);
+
+        for bucket in buckets.buckets.iter() {
+            assert!(bucket.nodes.len() <= 8);
+        }
+    }
+
+    fn random_table() -> Kbuckets {
+        let own_id = HashId::random();
+        let mut buckets = Kbuckets::new(own_id);
+
+        for n in 0..2000 {
+            // share the first n % 40 bits with our id, so that deep buckets get created
+            let shared = n % 40;
+            let random = HashId::random();
+            let distance = (random ^ random.masked(shared + 1)).flip_bit(shared);
+
+            let _ = buckets.try_insert(get_node((own_id ^ distance).hash));
+        }
+
+        buckets
+    }
+
+    #[test]
+    fn test_buckets_cover_whole_space() {
+        for _ in 0..10 {
+            let buckets = random_table();
+            let mut ranges = buckets
+                .buckets
+                .iter()
+                .map(|bucket| (bucket.lower_boundary(), bucket.upper_boundary()))
+                .collect::<Vec<(HashId, HashId)>>();
+
+            ranges.sort_unstable();
+
+            assert!(ranges.len() > 10);
+            assert_eq!(ranges[0].0, HashId::new([0; 20]));
+            assert_eq!(ranges[ranges.len() - 1].1, HashId::new([255; 20]));
+
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].1.successor(), pair[1].0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_id_belongs_to_exactly_one_bucket() {
+        let buckets = random_table();
+
+        for _ in 0..1000 {
+            let id = HashId::random();
+            let containing = buckets
+                .buckets
+                .iter()
+                .filter(|bucket| bucket.contains(&id))
+                .count();
+
+            assert_eq!(containing, 1);
+            assert!(buckets.find(&id).contains(&id));
+        }
+
+        for bucket in buckets.buckets.iter() {
+            for node in bucket.nodes.iter() {
+                assert!(bucket.contains(&node.node_id));
+            }
+        }
+    }
+
+    fn brute_force_closest<F>(buckets: &Kbuckets, id: &HashId, count: usize, filter: F) -> Vec<HashId>
+    where
+        F: Fn(&Node) -> bool,
+    {
+        let mut all = buckets
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .filter(|node| filter(node))
+            .map(|node| node.node_id)
+            .collect::<Vec<HashId>>();
+
+        all.sort_unstable_by_key(|node_id| *node_id ^ *id);
+        all.truncate(count);
+        all
+    }
+
+    fn ids(nodes: Vec<Node>) -> Vec<HashId> {
+        nodes.iter().map(|node| node.node_id).collect()
+    }
+
+    #[test]
+    fn test_closest_nodes_match_brute_force() {
+        for _ in 0..5 {
+            let buckets = random_table();
+            let own_id = buckets.own_id;
+
+            for n in 0..40 {
+                // mix targets anywhere in the space with targets close to our own id
+                let random = HashId::random();
+                let target = if n % 2 == 0 {
+                    random
+                } else {
+                    own_id ^ random ^ random.masked(n)
+                };
+
+                for count in [1, 8, 20].iter() {
+                    assert_eq!(
+                        ids(buckets.find_closest_nodes_matching(&target, *count, |_| true)),
+                        brute_force_closest(&buckets, &target, *count, |_| true)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_filtered_closest_nodes_match_brute_force() {
+        let even = |node: &Node| node.node_id.hash[19] & 1 == 0;
+
+        for _ in 0..5 {
+            let buckets = random_table();
+
+            for _ in 0..40 {
+                let target = HashId::random();
+
+                assert_eq!(
+                    ids(buckets.find_closest_nodes_matching(&target, 8, even)),
+                    brute_force_closest(&buckets, &target, 8, even)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_closest_nodes_of_small_table() {
+        let mut buckets = Kbuckets::new(HashId::new([0; 20]));
+
+        assert!(buckets.find_closest_nodes(&HashId::random()).is_empty());
+
+        buckets.try_insert(get_node([255; 20])).unwrap();
+        buckets.try_insert(get_node([1; 20])).unwrap();
+
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(
+            ids(buckets.find_closest_nodes(&HashId::new([254; 20]))),
+            vec![HashId::new([255; 20]), HashId::new([1; 20])]
+        );
+    }
+
+    fn full_foreign_bucket() -> (Kbuckets, ManualClock) {
+        let (mut buckets, clock) = table();
+
+        for n in 0..8 {
+            buckets.try_insert(get_node([128 + n; 20])).unwrap();
+        }
+
+        (buckets, clock)
+    }
+
+    #[test]
+    fn test_report_questionable_nodes_of_full_bucket() {
+        let (mut buckets, clock) = full_foreign_bucket();
+        buckets.try_insert(get_node([1; 20])).unwrap();
+
+        // 130 answered twenty minutes ago and 133 sixteen minutes ago
+        clock.advance(4 * MINUTE);
+        respond(&mut buckets, (128..136).filter(|n| *n != 130), clock.now());
+        clock.advance(16 * MINUTE);
+        respond(&mut buckets, (128..136).filter(|n| *n != 130 && *n != 133), clock.now());
+
+        match buckets.try_insert(get_node([200; 20])).unwrap() {
+            Insertion::Cached(questionable) => {
+                assert_eq!(questionable.len(), 2);
+                assert_eq!(questionable[0].node_id, HashId::new([130; 20]));
+                assert_eq!(questionable[1].node_id, HashId::new([133; 20]));
+            }
+            insertion => panic!("unexpected insertion {:?}", insertion),
+        }
+    }
+
+    #[test]
+    fn test_promote_replacement_on_eviction() {
+        let (mut buckets, _) = full_foreign_bucket();
+        buckets.try_insert(get_node([1; 20])).unwrap();
+
+        buckets.try_insert(get_node([200; 20])).unwrap();
+        buckets.try_insert(get_node([201; 20])).unwrap();
+
+        let promoted = buckets.evict(&HashId::new([130; 20])).unwrap();
+
+        assert_eq!(promoted.node_id, HashId::new([201; 20]));
+        assert_eq!(buckets.buckets[0].nodes.len(), 8);
+        assert!(buckets.buckets[0].find_mut(&HashId::new([130; 20])).is_none());
+        assert!(buckets.buckets[0].find_mut(&HashId::new([201; 20])).is_some());
+        assert_eq!(buckets.buckets[0].replacements().count(), 1);
+
+        assert_eq!(buckets.evict(&HashId::new([201; 20])).unwrap().node_id, HashId::new([200; 20]));
+        assert!(buckets.evict(&HashId::new([200; 20])).is_none());
+        assert_eq!(buckets.buckets[0].nodes.len(), 7);
+    }
+
+    #[test]
+    fn test_replacement_cache_is_bounded() {
+        let mut bucket = Bucket::new(HashId::new([0; 20]), 0, Instant::now());
+
+        for n in 0..20 {
+            bucket.cache(get_node([n; 20]));
+        }
+        bucket.cache(get_node([15; 20]));
+
+        let cached = bucket.replacements().map(|node| node.node_id.hash[0]).collect::<Vec<u8>>();
+        assert_eq!(cached, vec![12, 13, 14, 16, 17, 18, 19, 15]);
+    }
+
+    #[test]
+    fn test_split_keeps_replacements() {
+        let mut buckets = Kbuckets::new(HashId::new([0; 20]));
+        buckets.buckets[0].cache(get_node([200; 20]));
+        buckets.buckets[0].cache(get_node([1; 20]));
+
+        buckets.split();
+
+        assert_eq!(buckets.buckets[0].replacements().next().unwrap().node_id, HashId::new([200; 20]));
+        assert_eq!(buckets.buckets[1].replacements().next().unwrap().node_id, HashId::new([1; 20]));
+    }
+
+    #[test]
+    fn test_prefer_good_nodes_in_answers() {
+        let (mut buckets, clock) = table();
+
+        for n in 1..12 {
+            buckets.try_insert(get_node([n; 20])).unwrap();
+        }
+
+        // all but the three closest nodes answered again twenty minutes later
+        clock.advance(20 * MINUTE);
+        respond(&mut buckets, 4..12, clock.now());
+
+        buckets.mark_failed(&HashId::new([4; 20]));
+        buckets.mark_failed(&HashId::new([4; 20]));
+
+        let closest = ids(buckets.find_closest_nodes(&HashId::new([0; 20])));
+
+        // seven good nodes, filled up with the closest questionable one, never the bad one
+        assert_eq!(closest.len(), 8);
+        assert!(closest.contains(&HashId::new([1; 20])));
+        assert!(!closest.contains(&HashId::new([2; 20])));
+        assert!(!closest.contains(&HashId::new([4; 20])));
+    }
+
+    #[test]
+    fn test_replace_bad_node_on_insert() {
+        let (mut buckets, _) = full_foreign_bucket();
+        buckets.try_insert(get_node([1; 20])).unwrap();
+
+        buckets.mark_failed(&HashId::new([130; 20]));
+        assert!(buckets.mark_failed(&HashId::new([130; 20])).is_none());
+
+        assert_eq!(buckets.try_insert(get_node([200; 20])).unwrap(), Insertion::Added);
+        assert!(buckets.buckets[0].find_mut(&HashId::new([130; 20])).is_none());
+        assert!(buckets.buckets[0].find_mut(&HashId::new([200; 20])).is_some());
+    }
+
+    #[test]
+    fn test_evict_bad_node_for_replacement() {
+        let (mut buckets, _) = full_foreign_bucket();
+        buckets.try_insert(get_node([1; 20])).unwrap();
+        buckets.try_insert(get_node([200; 20])).unwrap();
+
+        assert!(buckets.mark_failed(&HashId::new([130; 20])).is_none());
+
+        let promoted = buckets.mark_failed(&HashId::new([130; 20])).unwrap();
+        assert_eq!(promoted.node_id, HashId::new([200; 20]));
+        assert!(buckets.buckets[0].find_mut(&HashId::new([130; 20])).is_none());
+    }
+
+    #[test]
+    fn test_responses_update_node_and_bucket() {
+        let (mut buckets, clock) = table();
+        let id = HashId::new([5; 20]);
+        buckets.try_insert(Node::new(Endpoint::new("127.0.0.1", 4444).unwrap(), id)).unwrap();
+
+        assert_eq!(buckets.find_mut(&id).find_mut(&id).unwrap().state(clock.now()), NodeState::Questionable);
+
+        clock.advance(MINUTE);
+        buckets.mark_responded(&id);
+
+        assert_eq!(buckets.find_mut(&id).find_mut(&id).unwrap().state(clock.now()), NodeState::Good);
+        assert_eq!(buckets.find(&id).last_changed(), clock.now());
+    }
+
+    #[test]
+    fn test_refresh_stale_buckets() {
+        let (mut buckets, clock) = full_foreign_bucket();
+        buckets.try_insert(get_node([1; 20])).unwrap();
+        assert!(buckets.refresh().is_empty());
+
+        // the nodes answer without changing the bucket, except 130 which stays silent
+        clock.advance(Bucket::REFRESH_INTERVAL + MINUTE);
+        respond(&mut buckets, (128..136).filter(|n| *n != 130), clock.now());
+        buckets.buckets[1].last_changed = clock.now();
+
+        let refresh = buckets.refresh();
+        assert_eq!(refresh.len(), 1);
+
+        let (target, questionables) = &refresh[0];
+        assert!(buckets.buckets[0].contains(target));
+        assert_eq!(questionables.len(), 1);
+        assert_eq!(questionables[0].node_id, HashId::new([130; 20]));
+
+        // a refreshed bucket isn't stale again right away
+        assert_eq!(buckets.buckets[0].last_changed(), clock.now());
+        assert!(buckets.refresh().is_empty());
     }
 }
@@ -1,33 +1,42 @@
+use std::error;
 use std::fmt;
 
-pub struct BucketError {
-    message: String,
-}
+use super::message::MessageId;
 
-impl BucketError {
-    pub fn new(message: String) -> BucketError {
-        BucketError { message }
-    }
+pub enum DhtError {
+    BucketFull,
+    OutOfBucketRange,
+    InvalidHashId,
+    InvalidCompactNode,
+    TruncatedNodeList(usize),
+    InvalidMessage(String),
+    MalformedQuery(MessageId, String),
+    Encoding(String),
+    TooManyTransactions,
 }
 
-impl fmt::Debug for BucketError {
+impl fmt::Display for DhtError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.message)
+        match self {
+            DhtError::BucketFull => write!(f, "Bucket is already full"),
+            DhtError::OutOfBucketRange => write!(f, "NodeID is not within the buckets boundary"),
+            DhtError::InvalidHashId => write!(f, "Invalid HashId"),
+            DhtError::InvalidCompactNode => write!(f, "Invalid compact node"),
+            DhtError::TruncatedNodeList(len) => {
+                write!(f, "Compact node list of {} bytes is not a multiple of 26", len)
+            }
+            DhtError::InvalidMessage(reason) => write!(f, "Invalid message: {}", reason),
+            DhtError::MalformedQuery(_, reason) => write!(f, "Malformed query: {}", reason),
+            DhtError::Encoding(reason) => write!(f, "Can't encode message: {}", reason),
+            DhtError::TooManyTransactions => write!(f, "Too many pending queries"),
+        }
     }
 }
 
-pub struct InvalidHashIdError {}
-
-impl fmt::Debug for InvalidHashIdError {
+impl fmt::Debug for DhtError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Invalid HashId")
+        write!(f, "{}", self)
     }
 }
 
-pub struct InvalidCompactNodeError {}
-
-impl fmt::Debug for InvalidCompactNodeError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Invalid compact node")
-    }
-}
+impl error::Error for DhtError {}
@@ -0,0 +1,331 @@
+use super::bucket::Bucket;
+use super::node::*;
+use super::token::Token;
+use super::util::*;
+
+use std::collections::HashSet;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LookupKind {
+    FindNode,
+    GetPeers,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum CandidateState {
+    Unqueried,
+    Pending,
+    Responded,
+    Failed,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    node: Node,
+    state: CandidateState,
+    token: Option<Token>,
+}
+
+/// An iterative Kademlia lookup, it only decides whom to ask next and leaves the sending to the handler.
+#[derive(Debug)]
+pub struct Lookup {
+    kind: LookupKind,
+    target: HashId,
+    alpha: usize,
+    k: usize,
+    candidates: Vec<Candidate>,
+    peers: HashSet<Endpoint>,
+}
+
+impl Lookup {
+    pub const ALPHA: usize = 3;
+    pub const K: usize = Bucket::SIZE;
+
+    pub fn new(kind: LookupKind, target: HashId) -> Lookup {
+        Lookup::with_parameters(kind, target, Lookup::ALPHA, Lookup::K)
+    }
+
+    pub fn with_parameters(kind: LookupKind, target: HashId, alpha: usize, k: usize) -> Lookup {
+        Lookup {
+            kind,
+            target,
+            alpha: alpha.max(1),
+            k: k.max(1),
+            candidates: Vec::new(),
+            peers: HashSet::new(),
+        }
+    }
+
+    pub fn kind(&self) -> LookupKind {
+        self.kind
+    }
+
+    pub fn target(&self) -> HashId {
+        self.target
+    }
+
+    /// Only unqueried candidates among the k·alpha closest that didn't fail are kept, so neither the
+    /// list nor the duplicate check grows with what remote nodes send us.
+    pub fn add_candidates(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            if self.candidates.iter().any(|candidate| candidate.node.node_id == node.node_id) {
+                continue;
+            }
+
+            self.candidates.push(Candidate {
+                node: *node,
+                state: CandidateState::Unqueried,
+                token: None,
+            });
+        }
+
+        let target = self.target;
+        self.candidates.sort_unstable_by_key(|candidate| candidate.node.node_id ^ target);
+
+        // queried candidates stay, so answers still match and no node is asked twice
+        let limit = self.k * self.alpha;
+        let mut position = 0;
+        self.candidates.retain(|candidate| {
+            if candidate.state != CandidateState::Failed {
+                position += 1;
+            }
+            position <= limit || candidate.state != CandidateState::Unqueried
+        });
+    }
+
+    /// Picks the closest unqueried candidates, at most alpha queries are in flight at once.
+    pub fn next_queries(&mut self) -> Vec<Node> {
+        let in_flight = self.count(CandidateState::Pending);
+        let (alpha, k) = (self.alpha, self.k);
+        let mut queries = Vec::new();
+
+        for candidate in self.closest_candidates_mut().take(k) {
+            if in_flight + queries.len() >= alpha {
+                break;
+            }
+
+            if candidate.state == CandidateState::Unqueried {
+                candidate.state = CandidateState::Pending;
+                queries.push(candidate.node);
+            }
+        }
+
+        queries
+    }
+
+    pub fn responded(&mut self, id: &HashId, nodes: &[Node]) {
+        self.set_state(id, CandidateState::Responded);
+        self.add_candidates(&nodes[..nodes.len().min(self.k)]);
+    }
+
+    pub fn failed(&mut self, id: &HashId) {
+        self.set_state(id, CandidateState::Failed);
+    }
+
+    /// Remembers the token a node handed out, announcing to it requires it later.
+    pub fn set_token(&mut self, id: &HashId, token: Token) {
+        if let Some(candidate) = self.find_mut(id) {
+            candidate.token = Some(token);
+        }
+    }
+
+    pub fn token(&self, id: &HashId) -> Option<&Token> {
+        self.candidates
+            .iter()
+            .find(|candidate| candidate.node.node_id == *id)
+            .and_then(|candidate| candidate.token.as_ref())
+    }
+
+    /// Returns the peers that weren't reported by an earlier response.
+    pub fn add_peers(&mut self, peers: &[Endpoint]) -> Vec<Endpoint> {
+        peers.iter().filter(|peer| self.peers.insert(**peer)).copied().collect()
+    }
+
+    pub fn peers(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// The lookup is done once the k closest candidates that didn't fail have all answered.
+    pub fn is_finished(&self) -> bool {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.state != CandidateState::Failed)
+            .take(self.k)
+            .all(|candidate| candidate.state == CandidateState::Responded)
+    }
+
+    /// The k closest nodes that answered, ordered by distance to the target.
+    pub fn closest(&self) -> Vec<Node> {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.state == CandidateState::Responded)
+            .take(self.k)
+            .map(|candidate| candidate.node)
+            .collect()
+    }
+
+    /// The k closest nodes that answered with a token, the ones we may announce to.
+    pub fn closest_with_tokens(&self) -> Vec<(Node, Token)> {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.state == CandidateState::Responded)
+            .filter_map(|candidate| candidate.token.clone().map(|token| (candidate.node, token)))
+            .take(self.k)
+            .collect()
+    }
+
+    fn closest_candidates_mut(&mut self) -> impl Iterator<Item = &mut Candidate> {
+        self.candidates.iter_mut().filter(|candidate| candidate.state != CandidateState::Failed)
+    }
+
+    fn count(&self, state: CandidateState) -> usize {
+        self.candidates.iter().filter(|candidate| candidate.state == state).count()
+    }
+
+    fn set_state(&mut self, id: &HashId, state: CandidateState) {
+        if let Some(candidate) = self.find_mut(id) {
+            candidate.state = state;
+        }
+    }
+
+    fn find_mut(&mut self, id: &HashId) -> Option<&mut Candidate> {
+        self.candidates.iter_mut().find(|candidate| candidate.node.node_id == *id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: u8) -> Node {
+        Node::new(Endpoint::new("10.0.0.1", 6000 + id as u16).unwrap(), HashId::new([id; 20]))
+    }
+
+    fn nodes(ids: &[u8]) -> Vec<Node> {
+        ids.iter().map(|id| node(*id)).collect()
+    }
+
+    fn ids(nodes: Vec<Node>) -> Vec<u8> {
+        nodes.iter().map(|node| node.node_id.hash[0]).collect()
+    }
+
+    #[test]
+    fn test_query_closest_candidates_alpha_at_a_time() {
+        let mut lookup = Lookup::new(LookupKind::FindNode, HashId::new([0; 20]));
+        lookup.add_candidates(&nodes(&[9, 1, 7, 3, 5]));
+
+        assert_eq!(ids(lookup.next_queries()), vec![1, 3, 5]);
+        assert!(lookup.next_queries().is_empty());
+
+        lookup.responded(&HashId::new([1; 20]), &[]);
+        assert_eq!(ids(lookup.next_queries()), vec![7]);
+    }
+
+    #[test]
+    fn test_closer_nodes_are_queried_first() {
+        let mut lookup = Lookup::with_parameters(LookupKind::FindNode, HashId::new([0; 20]), 1, 2);
+        lookup.add_candidates(&nodes(&[8, 9]));
+
+        assert_eq!(ids(lookup.next_queries()), vec![8]);
+        lookup.responded(&HashId::new([8; 20]), &nodes(&[2, 8]));
+
+        assert_eq!(ids(lookup.next_queries()), vec![2]);
+        lookup.responded(&HashId::new([2; 20]), &nodes(&[1]));
+
+        assert_eq!(ids(lookup.next_queries()), vec![1]);
+        lookup.responded(&HashId::new([1; 20]), &[]);
+
+        // 9 is farther than the two closest responders, so it's never asked
+        assert!(lookup.is_finished());
+        assert!(lookup.next_queries().is_empty());
+        assert_eq!(ids(lookup.closest()), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_failed_candidates_are_replaced() {
+        let mut lookup = Lookup::with_parameters(LookupKind::FindNode, HashId::new([0; 20]), 3, 2);
+        lookup.add_candidates(&nodes(&[1, 2, 3]));
+
+        assert_eq!(ids(lookup.next_queries()), vec![1, 2]);
+        lookup.failed(&HashId::new([1; 20]));
+        lookup.responded(&HashId::new([2; 20]), &[]);
+
+        assert!(!lookup.is_finished());
+        assert_eq!(ids(lookup.next_queries()), vec![3]);
+
+        lookup.responded(&HashId::new([3; 20]), &[]);
+        assert!(lookup.is_finished());
+        assert_eq!(ids(lookup.closest()), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_finish_when_candidates_run_out() {
+        let mut lookup = Lookup::new(LookupKind::FindNode, HashId::new([0; 20]));
+        assert!(lookup.is_finished());
+
+        lookup.add_candidates(&nodes(&[1]));
+        lookup.next_queries();
+        assert!(!lookup.is_finished());
+
+        lookup.failed(&HashId::new([1; 20]));
+        assert!(lookup.is_finished());
+        assert!(lookup.closest().is_empty());
+    }
+
+    #[test]
+    fn test_report_new_peers_once() {
+        let mut lookup = Lookup::new(LookupKind::GetPeers, HashId::new([0; 20]));
+        let peer = |port| Endpoint::new("10.0.2.1", port).unwrap();
+
+        assert_eq!(lookup.add_peers(&[peer(1), peer(2)]), vec![peer(1), peer(2)]);
+        assert_eq!(lookup.add_peers(&[peer(2), peer(3), peer(3)]), vec![peer(3)]);
+        assert_eq!(lookup.peers(), 3);
+    }
+
+    #[test]
+    fn test_remember_tokens_of_candidates() {
+        let mut lookup = Lookup::new(LookupKind::GetPeers, HashId::new([0; 20]));
+        lookup.add_candidates(&nodes(&[1, 2]));
+
+        lookup.set_token(&HashId::new([1; 20]), b"ab".to_vec());
+        lookup.set_token(&HashId::new([9; 20]), b"cd".to_vec());
+
+        assert_eq!(lookup.token(&HashId::new([1; 20])), Some(&b"ab".to_vec()));
+        assert_eq!(lookup.token(&HashId::new([2; 20])), None);
+        assert_eq!(lookup.token(&HashId::new([9; 20])), None);
+    }
+
+    #[test]
+    fn test_closest_responders_with_tokens() {
+        let mut lookup = Lookup::with_parameters(LookupKind::GetPeers, HashId::new([0; 20]), 3, 2);
+        lookup.add_candidates(&nodes(&[1, 2, 3, 4]));
+        lookup.next_queries();
+
+        lookup.responded(&HashId::new([1; 20]), &[]);
+        lookup.set_token(&HashId::new([2; 20]), b"b".to_vec());
+        lookup.responded(&HashId::new([2; 20]), &[]);
+        lookup.set_token(&HashId::new([3; 20]), b"c".to_vec());
+        lookup.responded(&HashId::new([3; 20]), &[]);
+
+        let closest = lookup.closest_with_tokens();
+        assert_eq!(ids(closest.iter().map(|(node, _)| *node).collect()), vec![2, 3]);
+        assert_eq!(closest[0].1, b"b".to_vec());
+    }
+
+    #[test]
+    fn test_bound_candidates() {
+        let mut lookup = Lookup::with_parameters(LookupKind::FindNode, HashId::new([0; 20]), 1, 2);
+        let candidates = |lookup: &Lookup| ids(lookup.candidates.iter().map(|candidate| candidate.node).collect());
+        lookup.add_candidates(&nodes(&[8, 9]));
+        assert_eq!(ids(lookup.next_queries()), vec![8]);
+
+        // only the first k nodes of a response count
+        lookup.responded(&HashId::new([8; 20]), &nodes(&[7, 6, 1]));
+        assert_eq!(candidates(&lookup), vec![6, 7, 8]);
+        assert_eq!(ids(lookup.next_queries()), vec![6]);
+
+        // queried candidates survive although they're no longer among the k·alpha closest
+        lookup.add_candidates(&nodes(&[2, 3, 4]));
+        assert_eq!(candidates(&lookup), vec![2, 3, 6, 8]);
+    }
+}
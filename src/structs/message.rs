@@ -1,104 +1,456 @@
-use serde::{Deserialize, Serialize};
+use serde::de::{self, IgnoredAny, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_bencode;
+use std::convert::TryInto;
+use std::fmt;
 
-use super::util::*;
+use super::error::DhtError;
+use super::node::{CompactNodeList, Endpoint};
+use super::token::Token;
+use super::util::HashId;
 
-pub type MessageId = String;
-pub type ClientIdentifier = String;
+pub type MessageId = Vec<u8>;
+pub type ClientIdentifier = Vec<u8>;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct ErrorResponse(u8, String);
 
 impl ErrorResponse {
 	pub fn new(code: u8, message: String) -> ErrorResponse {
 		ErrorResponse(code, message)
 	}
+
+	pub fn code(&self) -> u8 {
+		self.0
+	}
+
+	pub fn message(&self) -> &str {
+		&self.1
+	}
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
+impl<'de> Deserialize<'de> for ErrorResponse {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ErrorResponse, D::Error> {
+        struct ErrorResponseVisitor;
+
+        impl<'de> Visitor<'de> for ErrorResponseVisitor {
+            type Value = ErrorResponse;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a list of an error code and a message")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ErrorResponse, A::Error> {
+                let code = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let message = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+
+                // the bencode decoder only consumes the end of a list once it is drained
+                while seq.next_element::<IgnoredAny>()?.is_some() {}
+
+                Ok(ErrorResponse(code, message))
+            }
+        }
+
+        deserializer.deserialize_seq(ErrorResponseVisitor)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Query {
     FindNode {
-        id: String,
-        target: String,
+        id: HashId,
+        target: HashId,
     },
     GetPeers {
-        id: String,
-        info_hash: String,
+        id: HashId,
+        info_hash: HashId,
     },
     AnnouncePeer {
-        id: String,
+        id: HashId,
         implied_port: Option<bool>,
         port: u16,
-        token: String,
-        info_hash: String
+        token: Token,
+        info_hash: HashId
     },
     Ping {
-        id: String,
+        id: HashId,
     },
 }
 
+impl Query {
+    const METHODS: [&'static [u8]; 4] = [b"ping", b"find_node", b"get_peers", b"announce_peer"];
+
+    pub fn method(&self) -> &'static str {
+        match self {
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::Ping { .. } => "ping",
+        }
+    }
+
+    fn from_arguments(method: &[u8], args: Arguments) -> Result<Query, DhtError> {
+        let id = required(args.id, "id")?;
+
+        Ok(match method {
+            b"ping" => Query::Ping { id },
+            b"find_node" => Query::FindNode {
+                id,
+                target: required(args.target, "target")?,
+            },
+            b"get_peers" => Query::GetPeers {
+                id,
+                info_hash: required(args.info_hash, "info_hash")?,
+            },
+            b"announce_peer" => Query::AnnouncePeer {
+                id,
+                implied_port: args.implied_port.map(|implied| implied != 0),
+                port: required(args.port, "port")?,
+                token: required(args.token, "token")?,
+                info_hash: required(args.info_hash, "info_hash")?,
+            },
+            _ => {
+                return Err(DhtError::InvalidMessage(format!(
+                    "unknown method {}",
+                    String::from_utf8_lossy(method)
+                )))
+            }
+        })
+    }
+
+    fn to_arguments(&self) -> Arguments {
+        match self {
+            Query::Ping { id } => Arguments::new(*id),
+            Query::FindNode { id, target } => Arguments {
+                target: Some(*target),
+                ..Arguments::new(*id)
+            },
+            Query::GetPeers { id, info_hash } => Arguments {
+                info_hash: Some(*info_hash),
+                ..Arguments::new(*id)
+            },
+            Query::AnnouncePeer {
+                id,
+                implied_port,
+                port,
+                token,
+                info_hash,
+            } => Arguments {
+                implied_port: implied_port.map(u8::from),
+                port: Some(*port),
+                token: Some(token.clone()),
+                info_hash: Some(*info_hash),
+                ..Arguments::new(*id)
+            },
+        }
+    }
+}
+
+fn required<T>(value: Option<T>, field: &'static str) -> Result<T, DhtError> {
+    value.ok_or_else(|| DhtError::InvalidMessage(format!("missing field {}", field)))
+}
+
+/// Union of the arguments of all supported queries, the `q` key decides which are required.
 #[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
+struct Arguments {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<HashId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<HashId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<HashId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    token: Option<Token>,
+}
+
+impl Arguments {
+    fn new(id: HashId) -> Arguments {
+        Arguments {
+            id: Some(id),
+            target: None,
+            info_hash: None,
+            implied_port: None,
+            port: None,
+            token: None,
+        }
+    }
+}
+
+/// A response typed by the query it answers, the wire format alone can't tell them apart.
+#[derive(Clone, Debug, PartialEq)]
 pub enum Response {
+    /// Nodes may send the closest nodes they know along with the peers.
     FoundPeers {
-        id: String,
-        token: String,
-        values: Vec<String>,
+        id: HashId,
+        token: Token,
+        values: Vec<Endpoint>,
+        nodes: Option<CompactNodeList>,
     },
     FoundPeerNodes {
-        id: String,
-        token: String,
-        nodes: String,
+        id: HashId,
+        token: Token,
+        nodes: CompactNodeList,
     },
     FoundNodes {
-        id: String,
-        nodes: String,
+        id: HashId,
+        nodes: CompactNodeList,
     },
     Empty {
-        id: String,
+        id: HashId,
     },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "y")]
+impl Response {
+    pub fn id(&self) -> HashId {
+        match self {
+            Response::FoundPeers { id, .. }
+            | Response::FoundPeerNodes { id, .. }
+            | Response::FoundNodes { id, .. }
+            | Response::Empty { id } => *id,
+        }
+    }
+
+    /// Decodes the return values of a response to a query of the given method.
+    pub fn from_values(method: &str, values: ReturnValues) -> Result<Response, DhtError> {
+        let id = required(values.id, "id")?;
+
+        Ok(match method {
+            "ping" | "announce_peer" => Response::Empty { id },
+            "find_node" => Response::FoundNodes {
+                id,
+                nodes: required(values.nodes, "nodes")?,
+            },
+            "get_peers" => {
+                let token = required(values.token, "token")?;
+
+                match (values.values, values.nodes) {
+                    (Some(values), nodes) => Response::FoundPeers { id, token, values, nodes },
+                    (None, Some(nodes)) => Response::FoundPeerNodes { id, token, nodes },
+                    (None, None) => return Err(DhtError::InvalidMessage("missing field values or nodes".to_string())),
+                }
+            }
+            _ => return Err(DhtError::InvalidMessage(format!("unknown method {}", method))),
+        })
+    }
+
+    pub fn to_values(&self) -> ReturnValues {
+        match self {
+            Response::Empty { id } => ReturnValues::new(*id),
+            Response::FoundNodes { id, nodes } => ReturnValues {
+                nodes: Some(nodes.clone()),
+                ..ReturnValues::new(*id)
+            },
+            Response::FoundPeers { id, token, values, nodes } => ReturnValues {
+                token: Some(token.clone()),
+                values: Some(values.clone()),
+                nodes: nodes.clone(),
+                ..ReturnValues::new(*id)
+            },
+            Response::FoundPeerNodes { id, token, nodes } => ReturnValues {
+                token: Some(token.clone()),
+                nodes: Some(nodes.clone()),
+                ..ReturnValues::new(*id)
+            },
+        }
+    }
+}
+
+/// Union of the return values of all responses, only the query they answer tells which are required.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReturnValues {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<HashId>,
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    token: Option<Token>,
+    #[serde(default, deserialize_with = "compact_peers", skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<Endpoint>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<CompactNodeList>,
+}
+
+/// Skips peers that aren't 6 byte compact IPv4 infos, dual stack nodes mix in 18 byte IPv6 ones.
+fn compact_peers<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Endpoint>>, D::Error> {
+    let peers = Vec::<serde_bytes::ByteBuf>::deserialize(deserializer)?;
+
+    Ok(Some(peers.iter().filter_map(|peer| peer.as_slice().try_into().ok().map(Endpoint::from_compact)).collect()))
+}
+
+impl ReturnValues {
+    fn new(id: HashId) -> ReturnValues {
+        ReturnValues {
+            id: Some(id),
+            token: None,
+            values: None,
+            nodes: None,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum Message {
-    #[serde(rename = "q")]
     Query {
-        #[serde(rename = "t")]
         id: MessageId,
-        #[serde(rename = "v")]
         client: Option<ClientIdentifier>,
-        #[serde(rename = "a")]
         args: Query,
     },
-    #[serde(rename = "e")]
+    UnknownQuery {
+        id: MessageId,
+        client: Option<ClientIdentifier>,
+        method: Vec<u8>,
+    },
     Error {
-        #[serde(rename = "t")]
         id: MessageId,
-        #[serde(rename = "v")]
         client: Option<ClientIdentifier>,
-        #[serde(rename = "e")]
         error: ErrorResponse,
     },
-    #[serde(rename = "r")]
     Response {
-        #[serde(rename = "t")]
         id: MessageId,
-        #[serde(rename = "v")]
         client: Option<ClientIdentifier>,
-        #[serde(rename = "r")]
-        response: Response,
+        values: ReturnValues,
     },
 }
 
+/// The keys every KRPC message has, used to answer messages that can't be decoded completely.
+#[derive(Debug, Deserialize)]
+struct Header {
+    #[serde(rename = "y", with = "serde_bytes")]
+    kind: Vec<u8>,
+    #[serde(rename = "t", with = "serde_bytes")]
+    id: MessageId,
+    #[serde(rename = "q", with = "serde_bytes", default)]
+    method: Option<Vec<u8>>,
+}
+
+/// A query with a method we don't support, only its arguments' sender id is required.
+#[derive(Debug, Deserialize)]
+struct UnknownQueryEnvelope {
+    #[serde(rename = "v", with = "serde_bytes", default)]
+    client: Option<ClientIdentifier>,
+    #[serde(rename = "a")]
+    _args: UnknownArguments,
+}
+
+#[derive(Debug, Deserialize)]
+struct UnknownArguments {
+    #[serde(rename = "id")]
+    _id: IgnoredAny,
+}
+
+/// The KRPC dictionary as it is sent over the wire.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    #[serde(rename = "y", with = "serde_bytes")]
+    kind: Vec<u8>,
+    #[serde(rename = "t", with = "serde_bytes")]
+    id: MessageId,
+    #[serde(rename = "v", with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
+    client: Option<ClientIdentifier>,
+    #[serde(rename = "q", with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
+    method: Option<Vec<u8>>,
+    #[serde(rename = "a", default, skip_serializing_if = "Option::is_none")]
+    args: Option<Arguments>,
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    response: Option<ReturnValues>,
+    #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorResponse>,
+}
+
 impl Message {
-    pub fn from_str(input: String) -> Result<Message, serde_bencode::error::Error> {
-        serde_bencode::de::from_str::<Message>(&input)
+    pub fn from_bytes(input: &[u8]) -> Result<Message, DhtError> {
+        let header = serde_bencode::de::from_bytes::<Header>(input)
+            .map_err(|e| DhtError::InvalidMessage(e.to_string()))?;
+
+        match (Message::decode(input, &header), header.kind.as_slice()) {
+            (Err(DhtError::InvalidMessage(reason)), b"q") => {
+                Err(DhtError::MalformedQuery(header.id, reason))
+            }
+            (result, _) => result,
+        }
+    }
+
+    fn decode(input: &[u8], header: &Header) -> Result<Message, DhtError> {
+        if header.kind == b"q" {
+            let method = required(header.method.clone(), "q")?;
+
+            // unknown methods may use known keys with other types, their arguments aren't decoded
+            if !Query::METHODS.contains(&method.as_slice()) {
+                let query = serde_bencode::de::from_bytes::<UnknownQueryEnvelope>(input)
+                    .map_err(|e| DhtError::InvalidMessage(e.to_string()))?;
+
+                return Ok(Message::UnknownQuery { id: header.id.clone(), client: query.client, method });
+            }
+        }
+
+        let envelope = serde_bencode::de::from_bytes::<Envelope>(input)
+            .map_err(|e| DhtError::InvalidMessage(e.to_string()))?;
+        let id = envelope.id;
+        let client = envelope.client;
+
+        match envelope.kind.as_slice() {
+            b"q" => Ok(Message::Query {
+                args: Query::from_arguments(&required(envelope.method, "q")?, required(envelope.args, "a")?)?,
+                id,
+                client,
+            }),
+            b"r" => Ok(Message::Response {
+                values: required(envelope.response, "r")?,
+                id,
+                client,
+            }),
+            b"e" => Ok(Message::Error {
+                error: required(envelope.error, "e")?,
+                id,
+                client,
+            }),
+            kind => Err(DhtError::InvalidMessage(format!(
+                "unknown message type {}",
+                String::from_utf8_lossy(kind)
+            ))),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DhtError> {
+        serde_bencode::to_bytes(&self.to_envelope()).map_err(|e| DhtError::Encoding(e.to_string()))
+    }
+
+    fn to_envelope(&self) -> Envelope {
+        match self {
+            Message::Query { id, client, args } => Envelope {
+                method: Some(args.method().as_bytes().to_vec()),
+                args: Some(args.to_arguments()),
+                ..Envelope::new(b"q", id, client)
+            },
+            Message::UnknownQuery { id, client, method } => Envelope {
+                method: Some(method.clone()),
+                ..Envelope::new(b"q", id, client)
+            },
+            Message::Response { id, client, values } => Envelope {
+                response: Some(values.clone()),
+                ..Envelope::new(b"r", id, client)
+            },
+            Message::Error { id, client, error } => Envelope {
+                error: Some(error.clone()),
+                ..Envelope::new(b"e", id, client)
+            },
+        }
     }
+}
 
-    pub fn to_str(&self) -> Result<String, serde_bencode::error::Error> {
-        serde_bencode::to_string(&self)
+impl Envelope {
+    fn new(kind: &[u8], id: &[u8], client: &Option<ClientIdentifier>) -> Envelope {
+        Envelope {
+            kind: kind.to_vec(),
+            id: id.to_vec(),
+            client: client.clone(),
+            method: None,
+            args: None,
+            response: None,
+            error: None,
+        }
     }
 }
 
@@ -108,18 +460,20 @@ mod tests {
 
     #[test]
     fn test_decode_ping() {
-        let input = "d1:ad2:id40:ffffffffffffffffffffffffffffffffffffffffe1:t2:aa1:v4:aa001:y1:qe"
-            .to_string();
-        let deserialize = Message::from_str(input).unwrap();
+        let input = [
+            b"d1:ad2:id20:".as_ref(),
+            &[255; 20],
+            b"e1:q4:ping1:t2:aa1:v4:aa001:y1:qe",
+        ]
+        .concat();
+        let deserialize = Message::from_bytes(&input).unwrap();
 
         match deserialize {
             Message::Query { id, client, args } => {
-                assert_eq!(id, "aa".to_owned());
-                assert_eq!(client.unwrap(), "aa00".to_owned());
+                assert_eq!(id, b"aa".to_vec());
+                assert_eq!(client.unwrap(), b"aa00".to_vec());
                 match args {
-                    Query::Ping { id } => {
-                        assert_eq!(HashId::from_str(id).unwrap(), HashId::new([255; 20]))
-                    }
+                    Query::Ping { id } => assert_eq!(id, HashId::new([255; 20])),
                     _ => panic!("wrong query"),
                 }
             }
@@ -129,16 +483,24 @@ mod tests {
 
     #[test]
     fn test_decode_find_node() {
-        let input = "d1:ad2:id40:ffffffffffffffffffffffffffffffffffffffff6:target3:fffe1:t2:aa1:v4:aa001:y1:qe".to_string();
-        let deserialize = Message::from_str(input).unwrap();
+        let input = [
+            b"d1:ad2:id20:".as_ref(),
+            &[255; 20],
+            b"6:target20:",
+            &[15; 20],
+            b"e1:q9:find_node1:t2:aa1:v4:aa001:y1:qe",
+        ]
+        .concat();
+        let deserialize = Message::from_bytes(&input).unwrap();
 
         match deserialize {
             Message::Query { id, client, args } => {
-                assert_eq!(id, "aa".to_owned());
-                assert_eq!(client.unwrap(), "aa00".to_owned());
+                assert_eq!(id, b"aa".to_vec());
+                assert_eq!(client.unwrap(), b"aa00".to_vec());
                 match args {
-                    Query::FindNode { id, target: _ } => {
-                        assert_eq!(HashId::from_str(id).unwrap(), HashId::new([255; 20]))
+                    Query::FindNode { id, target } => {
+                        assert_eq!(id, HashId::new([255; 20]));
+                        assert_eq!(target, HashId::new([15; 20]));
                     }
                     _ => panic!("wrong query"),
                 }
@@ -149,13 +511,13 @@ mod tests {
 
     #[test]
     fn test_decode_error() {
-        let input = "d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:v4:aa001:y1:ee".to_string();
-        let deserialize = Message::from_str(input).unwrap();
+        let input = "d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:v4:aa001:y1:ee".as_bytes();
+        let deserialize = Message::from_bytes(input).unwrap();
 
         match deserialize {
             Message::Error { id, client, error } => {
-                assert_eq!(id, "aa".to_owned());
-                assert_eq!(client.unwrap(), "aa00".to_owned());
+                assert_eq!(id, b"aa".to_vec());
+                assert_eq!(client.unwrap(), b"aa00".to_vec());
                 assert_eq!(error.0, 201);
                 assert_eq!(error.1, "A Generic Error Ocurred".to_owned());
             }
@@ -165,25 +527,33 @@ mod tests {
 
     #[test]
     fn test_decode_response() {
-        let input = "d1:rd2:id40:ffffffffffffffffffffffffffffffffffffffff5:nodes17:compact_node_info5:token6:secrete1:t2:aa1:v4:aa001:y1:re".to_string();
-        let deserialize = Message::from_str(input).unwrap();
+        let input = [
+            b"d1:rd2:id20:".as_ref(),
+            &[255; 20],
+            b"5:nodes26:",
+            &hex::decode("38636177a357835555a2be8b36b6a2c80bd2bd536a9d70e3b1d3").unwrap(),
+            b"5:token6:secrete1:t2:aa1:v4:aa001:y1:re",
+        ]
+        .concat();
+        let deserialize = Message::from_bytes(&input).unwrap();
 
         match deserialize {
             Message::Response {
                 id,
                 client,
-                response,
+                values,
             } => {
-                assert_eq!(id, "aa".to_owned());
-                assert_eq!(client.unwrap(), "aa00".to_owned());
-                match response {
+                assert_eq!(id, b"aa".to_vec());
+                assert_eq!(client.unwrap(), b"aa00".to_vec());
+                match Response::from_values("get_peers", values).unwrap() {
                     Response::FoundPeerNodes {
                         id: _,
                         token,
                         nodes,
                     } => {
-                        assert_eq!(token, "secret".to_owned());
-                        assert_eq!(nodes, "compact_node_info".to_owned());
+                        assert_eq!(token, b"secret".to_vec());
+                        assert_eq!(nodes.len(), 1);
+                        assert_eq!(nodes.nodes()[0].endpoint.port, 45523);
                     }
                     _ => {
                         panic!("wrong response");
@@ -198,16 +568,14 @@ mod tests {
     fn test_serialize_ping_response() {
         let node_id = HashId::new([17; 20]);
         let response = Message::Response {
-            id: "aa".to_owned(),
+            id: b"aa".to_vec(),
             client: None,
-            response: Response::Empty {
-                id: node_id.to_str(),
-            },
+            values: Response::Empty { id: node_id }.to_values(),
         };
 
-        let encoded = response.to_str().unwrap();
+        let encoded = response.to_bytes().unwrap();
         assert_eq!(
-            "d1:rd2:id40:1111111111111111111111111111111111111111e1:t2:aa1:y1:re".to_owned(),
+            [b"d1:rd2:id20:".as_ref(), &[17; 20], b"e1:t2:aa1:y1:re"].concat(),
             encoded
         );
     }
@@ -215,7 +583,7 @@ mod tests {
     #[test]
     fn test_serialize_error() {
         let response = Message::Error {
-            id: "aa".to_owned(),
+            id: b"aa".to_vec(),
             client: None,
             error: ErrorResponse(
                 203,
@@ -224,7 +592,287 @@ mod tests {
             ),
         };
 
-        let encoded = response.to_str().unwrap();
+        let encoded = String::from_utf8(response.to_bytes().unwrap()).unwrap();
         assert_eq!(encoded, "d1:eli203e75:Protocol Error, such as a malformed packet, invalid arguments, or bad tokene1:t2:aa1:y1:ee".to_string());
     }
+
+    #[test]
+    fn test_decode_binary_ids() {
+        let input = [
+            b"d1:ad2:id20:".as_ref(),
+            &[0x80, 0, 0xff, 0xc3, 0x28, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+            b"e1:q4:ping1:t2:\x00\xff1:v4:UT\xab\x121:y1:qe",
+        ]
+        .concat();
+
+        match Message::from_bytes(&input).unwrap() {
+            Message::Query { id, client, args: Query::Ping { id: sender } } => {
+                assert_eq!(id, vec![0x00, 0xff]);
+                assert_eq!(client.unwrap(), b"UT\xab\x12".to_vec());
+                assert_eq!(sender.hash[0..5], [0x80, 0, 0xff, 0xc3, 0x28]);
+            }
+            _ => panic!("wrong command"),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_found_peers() {
+        let response = Message::Response {
+            id: vec![0xff, 0x00],
+            client: None,
+            values: Response::FoundPeers {
+                id: HashId::new([17; 20]),
+                token: vec![0xde, 0xad],
+                values: vec![Endpoint::new("10.0.0.1", 6881).unwrap()],
+                nodes: None,
+            }
+            .to_values(),
+        };
+
+        let encoded = response.to_bytes().unwrap();
+
+        match Message::from_bytes(&encoded).unwrap() {
+            Message::Response { id, values, .. } => {
+                assert_eq!(id, vec![0xff, 0x00]);
+
+                match Response::from_values("get_peers", values).unwrap() {
+                    Response::FoundPeers { token, values, .. } => {
+                        assert_eq!(token, vec![0xde, 0xad]);
+                        assert_eq!(values, vec![Endpoint::new("10.0.0.1", 6881).unwrap()]);
+                    }
+                    _ => panic!("wrong response"),
+                }
+            }
+            _ => panic!("wrong response"),
+        }
+    }
+
+    #[test]
+    fn test_decode_by_method_name() {
+        let input = [
+            b"d1:ad2:id20:".as_ref(),
+            &[255; 20],
+            b"9:info_hash20:",
+            &[34; 20],
+            b"4:porti6881e6:target20:",
+            &[15; 20],
+            b"e1:q9:get_peers1:t2:aa1:y1:qe",
+        ]
+        .concat();
+
+        match Message::from_bytes(&input).unwrap() {
+            Message::Query { args: Query::GetPeers { id, info_hash }, .. } => {
+                assert_eq!(id, HashId::new([255; 20]));
+                assert_eq!(info_hash, HashId::new([34; 20]));
+            }
+            _ => panic!("wrong query"),
+        }
+    }
+
+    #[test]
+    fn test_reject_missing_arguments() {
+        let input = [
+            b"d1:ad2:id20:".as_ref(),
+            &[255; 20],
+            b"e1:q13:announce_peer1:t2:aa1:y1:qe",
+        ]
+        .concat();
+
+        assert!(Message::from_bytes(&input).is_err());
+    }
+
+    #[test]
+    fn test_serialize_query() {
+        let query = Message::Query {
+            id: b"aa".to_vec(),
+            client: None,
+            args: Query::FindNode {
+                id: HashId::new([17; 20]),
+                target: HashId::new([34; 20]),
+            },
+        };
+
+        assert_eq!(
+            query.to_bytes().unwrap(),
+            [
+                b"d1:ad2:id20:".as_ref(),
+                &[17; 20],
+                b"6:target20:",
+                &[34; 20],
+                b"e1:q9:find_node1:t2:aa1:y1:qe",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_announce_peer() {
+        let query = Message::Query {
+            id: b"aa".to_vec(),
+            client: None,
+            args: Query::AnnouncePeer {
+                id: HashId::new([17; 20]),
+                implied_port: Some(true),
+                port: 6881,
+                token: b"secret".to_vec(),
+                info_hash: HashId::new([34; 20]),
+            },
+        };
+
+        match Message::from_bytes(&query.to_bytes().unwrap()).unwrap() {
+            Message::Query {
+                args: Query::AnnouncePeer { implied_port, port, token, info_hash, .. },
+                ..
+            } => {
+                assert_eq!(implied_port, Some(true));
+                assert_eq!(port, 6881);
+                assert_eq!(token, b"secret".to_vec());
+                assert_eq!(info_hash, HashId::new([34; 20]));
+            }
+            _ => panic!("wrong query"),
+        }
+    }
+
+    #[test]
+    fn test_decode_unknown_method() {
+        let input = [
+            b"d1:ad2:id20:".as_ref(),
+            &[255; 20],
+            b"6:target20:",
+            &[15; 20],
+            b"e1:q17:sample_infohashes1:t2:aa1:y1:qe",
+        ]
+        .concat();
+
+        match Message::from_bytes(&input).unwrap() {
+            Message::UnknownQuery { id, method, .. } => {
+                assert_eq!(id, b"aa".to_vec());
+                assert_eq!(method, b"sample_infohashes".to_vec());
+            }
+            _ => panic!("wrong command"),
+        }
+    }
+
+    #[test]
+    fn test_decode_unknown_method_with_mistyped_known_key() {
+        let input = [
+            b"d1:ad2:id20:".as_ref(),
+            &[255; 20],
+            b"4:porti70000e6:target32:",
+            &[15; 32],
+            b"e1:q3:foo1:t2:aa1:y1:qe",
+        ]
+        .concat();
+
+        match Message::from_bytes(&input).unwrap() {
+            Message::UnknownQuery { method, .. } => assert_eq!(method, b"foo".to_vec()),
+            _ => panic!("wrong command"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_method_requires_sender_id() {
+        for input in [b"d1:q11:vendor_ping1:t2:aa1:y1:qe".as_ref(), b"d1:ad1:xi1ee1:q3:foo1:t2:aa1:y1:qe"].iter() {
+            match Message::from_bytes(input) {
+                Err(DhtError::MalformedQuery(id, _)) => assert_eq!(id, b"aa".to_vec()),
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_query_keeps_transaction_id() {
+        let input = [
+            b"d1:ad2:id19:".as_ref(),
+            &[255; 19],
+            b"e1:q4:ping1:t2:aa1:y1:qe",
+        ]
+        .concat();
+
+        match Message::from_bytes(&input) {
+            Err(DhtError::MalformedQuery(id, _)) => assert_eq!(id, b"aa".to_vec()),
+            _ => panic!("malformed query was not detected"),
+        }
+    }
+
+    #[test]
+    fn test_reject_garbage() {
+        assert!(Message::from_bytes(b"").is_err());
+        assert!(Message::from_bytes(b"d1:t2:aae").is_err());
+        assert!(Message::from_bytes(b"i42e").is_err());
+        assert!(Message::from_bytes(b"d1:t2:aa1:y1:re").is_err());
+        assert!(Message::from_bytes(b"d1:t2:aa1:y1:xe").is_err());
+    }
+
+    fn values(input: &[u8]) -> ReturnValues {
+        match Message::from_bytes(&[b"d1:rd".as_ref(), input, b"e1:t2:aa1:y1:re"].concat()).unwrap() {
+            Message::Response { values, .. } => values,
+            _ => panic!("wrong command"),
+        }
+    }
+
+    #[test]
+    fn test_decode_response_by_query() {
+        let nodes = [b"2:id20:".as_ref(), &[255; 20], b"5:nodes0:"].concat();
+
+        assert_eq!(
+            Response::from_values("find_node", values(&nodes)).unwrap(),
+            Response::FoundNodes { id: HashId::new([255; 20]), nodes: CompactNodeList::default() }
+        );
+        assert!(Response::from_values("get_peers", values(&nodes)).is_err());
+        assert_eq!(
+            Response::from_values("ping", values(&nodes)).unwrap(),
+            Response::Empty { id: HashId::new([255; 20]) }
+        );
+
+        let peer_nodes = [nodes.as_slice(), b"5:token2:xy"].concat();
+        assert_eq!(
+            Response::from_values("get_peers", values(&peer_nodes)).unwrap(),
+            Response::FoundPeerNodes { id: HashId::new([255; 20]), token: b"xy".to_vec(), nodes: CompactNodeList::default() }
+        );
+
+        let peers_and_nodes = [peer_nodes.as_slice(), b"6:valuesl6:\x0a\x00\x00\x01\x1a\xe1e"].concat();
+        assert_eq!(
+            Response::from_values("get_peers", values(&peers_and_nodes)).unwrap(),
+            Response::FoundPeers {
+                id: HashId::new([255; 20]),
+                token: b"xy".to_vec(),
+                values: vec![Endpoint::new("10.0.0.1", 6881).unwrap()],
+                nodes: Some(CompactNodeList::default()),
+            }
+        );
+
+        assert!(Response::from_values("find_node", values(b"5:nodes0:")).is_err());
+        assert!(Response::from_values("find_node", values(&[b"2:id20:".as_ref(), &[255; 20]].concat())).is_err());
+    }
+
+    #[test]
+    fn test_skip_peers_of_other_address_families() {
+        let input = [
+            b"2:id20:".as_ref(),
+            &[255; 20],
+            b"5:token2:xy6:valuesl6:\x0a\x00\x00\x01\x1a\xe118:",
+            &[1; 18],
+            b"6:\x0a\x00\x00\x02\x1a\xe1e",
+        ]
+        .concat();
+
+        match Response::from_values("get_peers", values(&input)).unwrap() {
+            Response::FoundPeers { values, .. } => assert_eq!(
+                values,
+                vec![Endpoint::new("10.0.0.1", 6881).unwrap(), Endpoint::new("10.0.0.2", 6881).unwrap()]
+            ),
+            _ => panic!("wrong response"),
+        }
+    }
+
+    #[test]
+    fn test_reject_truncated_node_list() {
+        let input = [b"d1:rd2:id20:".as_ref(), &[255; 20], b"5:nodes3:abce1:t2:aa1:y1:re"].concat();
+
+        match Message::from_bytes(&input) {
+            Err(DhtError::InvalidMessage(reason)) => assert!(reason.contains("not a multiple of 26")),
+            _ => panic!("truncated node list was accepted"),
+        }
+    }
 }
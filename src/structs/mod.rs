@@ -1,6 +1,12 @@
+pub mod announce;
+pub mod bootstrap;
 pub mod bucket;
+pub mod clock;
 pub mod error;
+pub mod lookup;
 pub mod message;
 pub mod node;
+pub mod peers;
 pub mod util;
 pub mod token;
+pub mod transaction;
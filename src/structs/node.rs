@@ -1,56 +1,107 @@
-use std::collections::HashMap;
 use super::error::*;
 use std::convert::TryInto;
 use std::fmt;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use super::util::*;
-use chrono::{DateTime, Duration, Utc};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NodeState {
+    Good,
+    Questionable,
+    Bad,
+}
 
 #[derive(Copy, Clone, Debug, Eq)]
 pub struct Node {
     pub endpoint: Endpoint,
     pub node_id: HashId,
-    pub last_seen: DateTime<Utc>,
+    pub last_seen: Option<Instant>,
+    pub last_response: Option<Instant>,
+    pub last_query: Option<Instant>,
     failed_queries: u8,
 }
 
 impl Node {
+    const MAX_FAILED_QUERIES: u8 = 2;
+    const GOOD_DURATION: Duration = Duration::from_secs(15 * 60);
+
     pub fn new(endpoint: Endpoint, node_id: HashId) -> Node {
         Node {
             endpoint,
             node_id,
-            last_seen: Utc::now(),
+            last_seen: None,
+            last_response: None,
+            last_query: None,
             failed_queries: 0,
         }
     }
 
-    pub fn from_str(encoded: String) -> Result<Node, InvalidCompactNodeError> {
-        hex::decode(encoded)
-            .map_err(|_| InvalidCompactNodeError {})
-            .map(
-                |compact: Vec<u8>| -> Result<Node, std::array::TryFromSliceError> {
-                    Ok(Node::new(
-                        Endpoint::from_compact(compact[20..26].try_into()?),
-                        HashId::new(compact[0..20].try_into()?),
-                    ))
-                },
-            )
-            .and_then(|result| result.map_err(|_| InvalidCompactNodeError {}))
+    pub const COMPACT_SIZE: usize = 26;
+
+    pub fn from_compact(compact: &[u8]) -> Result<Node, DhtError> {
+        if compact.len() != Node::COMPACT_SIZE {
+            return Err(DhtError::InvalidCompactNode);
+        }
+
+        Ok(Node::new(
+            Endpoint::from_compact(compact[20..26].try_into().unwrap()),
+            HashId::from_bytes(&compact[0..20]).map_err(|_| DhtError::InvalidCompactNode)?,
+        ))
+    }
+
+    pub fn to_compact(&self) -> [u8; 26] {
+        let mut output = [0; 26];
+
+        output[0..20].copy_from_slice(&self.node_id.hash);
+        output[20..26].copy_from_slice(&self.endpoint.to_compact());
+
+        output
+    }
+
+    pub fn state(&self, now: Instant) -> NodeState {
+        let recent = |time: Option<Instant>| match time {
+            Some(time) => now.saturating_duration_since(time) <= Node::GOOD_DURATION,
+            None => false,
+        };
+
+        if self.failed_queries >= Node::MAX_FAILED_QUERIES {
+            return NodeState::Bad;
+        }
+
+        // a node is good if it answered us lately, or answered us once and keeps querying us
+        if recent(self.last_response) || (self.last_response.is_some() && recent(self.last_query)) {
+            return NodeState::Good;
+        }
+
+        NodeState::Questionable
+    }
+
+    pub fn questionable(&self, now: Instant) -> bool {
+        self.state(now) == NodeState::Questionable
     }
 
-    pub fn to_str(&self) -> String {
-        let mut output = Vec::<u8>::new();
+    pub fn failed_queries(&self) -> u8 {
+        self.failed_queries
+    }
 
-        output.extend(self.node_id.hash.iter().copied());
-        output.extend(self.endpoint.to_compact().iter());
+    pub fn responded(&mut self, now: Instant) {
+        self.last_seen = Some(now);
+        self.last_response = Some(now);
+        self.failed_queries = 0;
+    }
 
-        hex::encode(output)
+    pub fn queried(&mut self, now: Instant) {
+        self.last_seen = Some(now);
+        self.last_query = Some(now);
     }
 
-    pub fn questionable(&self) -> bool {
-        Utc::now() - self.last_seen > Duration::minutes(15)
+    pub fn failed(&mut self) {
+        self.failed_queries = self.failed_queries.saturating_add(1);
     }
 
     pub fn distance(self, node: Node) -> HashId {
@@ -64,9 +115,53 @@ impl PartialEq for Node {
     }
 }
 
-pub type PeerList = HashMap<HashId, Vec<Endpoint>>;
+/// The `nodes` field of responses, any number of concatenated compact node infos.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompactNodeList {
+    nodes: Vec<Node>,
+}
 
-#[derive(Copy, Clone, Debug, Eq)]
+impl CompactNodeList {
+    pub fn new(nodes: Vec<Node>) -> CompactNodeList {
+        CompactNodeList { nodes }
+    }
+
+    pub fn from_bytes(compact: &[u8]) -> Result<CompactNodeList, DhtError> {
+        let chunks = compact.chunks_exact(Node::COMPACT_SIZE);
+
+        if !chunks.remainder().is_empty() {
+            return Err(DhtError::TruncatedNodeList(compact.len()));
+        }
+
+        Ok(CompactNodeList {
+            nodes: chunks.map(Node::from_compact).collect::<Result<Vec<Node>, DhtError>>()?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.nodes.iter().flat_map(Node::to_compact).collect()
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+impl From<Vec<Node>> for CompactNodeList {
+    fn from(nodes: Vec<Node>) -> CompactNodeList {
+        CompactNodeList::new(nodes)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Endpoint {
     pub port: u16,
     pub addr: Ipv4Addr,
@@ -80,6 +175,20 @@ impl Endpoint {
         })
     }
 
+    pub fn from_socket_addr(addr: SocketAddr) -> Option<Endpoint> {
+        match addr {
+            SocketAddr::V4(addr) => Some(Endpoint {
+                addr: *addr.ip(),
+                port: addr.port(),
+            }),
+            SocketAddr::V6(_) => None,
+        }
+    }
+
+    pub fn to_socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr.into(), self.port)
+    }
+
     pub fn from_compact(c: [u8; 6]) -> Endpoint {
         Endpoint {
             addr: Ipv4Addr::new(c[0], c[1], c[2], c[3]),
@@ -93,15 +202,59 @@ impl Endpoint {
 
         [a[0], a[1], a[2], a[3], p[0], p[1]]
     }
+}
 
-    pub fn to_string(&self) -> String {
-    	String::from_utf8(self.to_compact().to_vec()).unwrap()
+impl Serialize for Endpoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.to_compact())
     }
 }
 
-impl PartialEq for Endpoint {
-    fn eq(&self, other: &Self) -> bool {
-        self.addr == other.addr && self.port == other.port
+impl<'de> Deserialize<'de> for Endpoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Endpoint, D::Error> {
+        struct EndpointVisitor;
+
+        impl<'de> Visitor<'de> for EndpointVisitor {
+            type Value = Endpoint;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a 6 byte compact peer info")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Endpoint, E> {
+                v.try_into()
+                    .map(Endpoint::from_compact)
+                    .map_err(|_| E::invalid_length(v.len(), &self))
+            }
+        }
+
+        deserializer.deserialize_bytes(EndpointVisitor)
+    }
+}
+
+impl Serialize for CompactNodeList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.to_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for CompactNodeList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CompactNodeList, D::Error> {
+        struct CompactNodeListVisitor;
+
+        impl<'de> Visitor<'de> for CompactNodeListVisitor {
+            type Value = CompactNodeList;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "concatenated 26 byte compact node infos")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<CompactNodeList, E> {
+                CompactNodeList::from_bytes(v).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_bytes(CompactNodeListVisitor)
     }
 }
 
@@ -109,8 +262,8 @@ impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "NodeID: {}, last seen: {}, failed queries: {}, endpoint: {}",
-            self.node_id, self.last_seen, self.failed_queries, self.endpoint
+            "NodeID: {}, failed queries: {}, endpoint: {}",
+            self.node_id, self.failed_queries, self.endpoint
         )
     }
 }
@@ -124,7 +277,6 @@ impl fmt::Display for Endpoint {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::Duration;
 
     fn get_node() -> Node {
         Node::new(
@@ -134,10 +286,24 @@ mod tests {
     }
 
     #[test]
-    fn test_new_node_is_not_questionable() {
-        let node = get_node();
+    fn test_responding_node_is_not_questionable() {
+        let now = Instant::now();
+        let mut node = get_node();
+        node.responded(now);
 
-        assert_eq!(node.questionable(), false);
+        assert!(!node.questionable(now));
+        assert_eq!(node.state(now), NodeState::Good);
+    }
+
+    #[test]
+    fn test_unverified_node_is_questionable() {
+        let now = Instant::now();
+        let mut node = get_node();
+        assert_eq!(node.state(now), NodeState::Questionable);
+
+        // queries alone don't prove that the node is reachable
+        node.queried(now);
+        assert_eq!(node.state(now), NodeState::Questionable);
     }
 
     #[test]
@@ -148,36 +314,108 @@ mod tests {
 
     #[test]
     fn test_node_is_questionable_after_15_minutes() {
+        let now = Instant::now();
+        let mut node = get_node();
+        node.responded(now);
+
+        assert!(!node.questionable(now + Duration::from_secs(14 * 60)));
+        assert!(!node.questionable(now + Duration::from_secs(15 * 60)));
+        assert!(node.questionable(now + Duration::from_secs(15 * 60 + 1)));
+    }
+
+    #[test]
+    fn test_queries_keep_responded_node_good() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60 * 60);
+        let mut node = get_node();
+        node.responded(now);
+
+        assert!(node.questionable(later));
+
+        node.queried(later);
+        assert_eq!(node.state(later), NodeState::Good);
+    }
+
+    #[test]
+    fn test_node_is_bad_after_failed_queries() {
+        let now = Instant::now();
         let mut node = get_node();
+        node.responded(now);
 
-        node.last_seen = node
-            .last_seen
-            .checked_sub_signed(Duration::minutes(14))
-            .unwrap();
-        assert_eq!(node.questionable(), false);
+        node.failed();
+        assert_eq!(node.state(now), NodeState::Good);
 
-        node.last_seen = node
-            .last_seen
-            .checked_sub_signed(Duration::minutes(1))
-            .unwrap();
-        assert!(node.questionable());
+        node.failed();
+        assert_eq!(node.state(now), NodeState::Bad);
+        assert_eq!(node.failed_queries(), 2);
+
+        node.responded(now);
+        assert_eq!(node.state(now), NodeState::Good);
+        assert_eq!(node.failed_queries(), 0);
     }
 
     #[test]
     fn test_compact_node_info() {
         let original_node = get_node();
-        let compact = original_node.to_str();
+        let compact = original_node.to_compact();
 
-        let parsed_node = Node::from_str(compact);
+        let parsed_node = Node::from_compact(&compact);
         assert_eq!(original_node, parsed_node.unwrap());
     }
 
     #[test]
     fn test_parse_compact_node_info() {
-        let node =
-            Node::from_str("38636177a357835555a2be8b36b6a2c80bd2bd536a9d70e3b1d3".to_string())
-                .unwrap();
+        let compact = hex::decode("38636177a357835555a2be8b36b6a2c80bd2bd536a9d70e3b1d3").unwrap();
+        let node = Node::from_compact(&compact).unwrap();
 
         assert_eq!(node.endpoint.port, 45523);
     }
+
+    #[test]
+    fn test_reject_truncated_compact_node_info() {
+        assert!(Node::from_compact(&[17; 25]).is_err());
+    }
+
+    #[test]
+    fn test_endpoint_roundtrip() {
+        let endpoint = Endpoint::new("10.0.0.1", 6881).unwrap();
+        let encoded = serde_bencode::to_bytes(&endpoint).unwrap();
+
+        assert_eq!(encoded, b"6:\x0a\x00\x00\x01\x1a\xe1".to_vec());
+        assert_eq!(serde_bencode::from_bytes::<Endpoint>(&encoded).unwrap(), endpoint);
+    }
+
+    #[test]
+    fn test_compact_node_list_roundtrip() {
+        let nodes = (1..=8)
+            .map(|n| Node::new(Endpoint::new("10.0.0.1", 6880 + n as u16).unwrap(), HashId::new([n; 20])))
+            .collect::<Vec<Node>>();
+        let list = CompactNodeList::new(nodes.clone());
+
+        assert_eq!(list.to_bytes().len(), 8 * Node::COMPACT_SIZE);
+
+        let parsed = CompactNodeList::from_bytes(&list.to_bytes()).unwrap();
+        assert_eq!(parsed.nodes(), nodes.as_slice());
+        assert!(CompactNodeList::from_bytes(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reject_truncated_compact_node_list() {
+        let list = CompactNodeList::new(vec![get_node(), get_node()]).to_bytes();
+
+        match CompactNodeList::from_bytes(&list[..51]) {
+            Err(DhtError::TruncatedNodeList(51)) => {}
+            _ => panic!("truncated list was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_compact_node_list_serde() {
+        let list = CompactNodeList::new(vec![get_node()]);
+        let encoded = serde_bencode::to_bytes(&list).unwrap();
+
+        assert_eq!(&encoded[..3], b"26:");
+        assert_eq!(serde_bencode::from_bytes::<CompactNodeList>(&encoded).unwrap(), list);
+        assert!(serde_bencode::from_bytes::<CompactNodeList>(b"3:abc").is_err());
+    }
 }
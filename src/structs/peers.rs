@@ -0,0 +1,272 @@
+use super::node::Endpoint;
+use super::util::HashId;
+
+use rand::seq::IteratorRandom;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<Endpoint, Instant>,
+    requests: usize,
+}
+
+impl Swarm {
+    fn last_announce(&self) -> Option<Instant> {
+        self.peers.values().max().copied()
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self.peers.iter().min_by_key(|(_, announced)| **announced).map(|(peer, _)| *peer);
+
+        if let Some(peer) = oldest {
+            self.peers.remove(&peer);
+        }
+    }
+}
+
+/// The peers announced to us per info hash, an announce is forgotten unless it's renewed within the ttl.
+/// The store is bounded, when it's full the oldest peer of an info hash or the least requested info hash
+/// makes room for new announces.
+#[derive(Debug)]
+pub struct PeerStore {
+    ttl: Duration,
+    max_info_hashes: usize,
+    max_peers: usize,
+    swarms: HashMap<HashId, Swarm>,
+}
+
+impl Default for PeerStore {
+    fn default() -> PeerStore {
+        PeerStore::new()
+    }
+}
+
+impl PeerStore {
+    pub const TTL: Duration = Duration::from_secs(30 * 60);
+    pub const MAX_INFO_HASHES: usize = 2000;
+    pub const MAX_PEERS: usize = 500;
+    /// Compact peer infos take 8 bytes each in a response, 100 of them keep it below the MTU.
+    pub const MAX_VALUES: usize = 100;
+
+    pub fn new() -> PeerStore {
+        PeerStore {
+            ttl: PeerStore::TTL,
+            max_info_hashes: PeerStore::MAX_INFO_HASHES,
+            max_peers: PeerStore::MAX_PEERS,
+            swarms: HashMap::new(),
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Limits apply to new announces, stored peers beyond them are only dropped when they expire.
+    pub fn set_limits(&mut self, max_info_hashes: usize, max_peers: usize) {
+        self.max_info_hashes = max_info_hashes.max(1);
+        self.max_peers = max_peers.max(1);
+    }
+
+    /// Announcing the same endpoint again only renews it.
+    pub fn announce(&mut self, info_hash: HashId, peer: Endpoint, now: Instant) {
+        if !self.swarms.contains_key(&info_hash) && self.swarms.len() >= self.max_info_hashes {
+            self.evict_info_hash();
+        }
+
+        let max_peers = self.max_peers;
+        let swarm = self.swarms.entry(info_hash).or_default();
+
+        if !swarm.peers.contains_key(&peer) && swarm.peers.len() >= max_peers {
+            swarm.evict_oldest();
+        }
+
+        swarm.peers.insert(peer, now);
+    }
+
+    /// A random selection of at most `MAX_VALUES` peers that didn't expire yet.
+    pub fn get(&mut self, info_hash: &HashId, now: Instant) -> Vec<Endpoint> {
+        let ttl = self.ttl;
+
+        match self.swarms.get_mut(info_hash) {
+            Some(swarm) => {
+                swarm.requests += 1;
+
+                swarm
+                    .peers
+                    .iter()
+                    .filter(|(_, announced)| now.saturating_duration_since(**announced) < ttl)
+                    .map(|(peer, _)| *peer)
+                    .choose_multiple(&mut rand::thread_rng(), PeerStore::MAX_VALUES)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Drops expired announces and info hashes without peers, returns the number of dropped peers.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let ttl = self.ttl;
+        let before = self.len();
+
+        for swarm in self.swarms.values_mut() {
+            swarm.peers.retain(|_, announced| now.saturating_duration_since(*announced) < ttl);
+        }
+        self.swarms.retain(|_, swarm| !swarm.peers.is_empty());
+
+        before - self.len()
+    }
+
+    pub fn info_hashes(&self) -> usize {
+        self.swarms.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.swarms.values().map(|swarm| swarm.peers.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.swarms.is_empty()
+    }
+
+    /// Drops the least requested info hash, the one that wasn't announced for the longest time among equals.
+    fn evict_info_hash(&mut self) {
+        let evicted = self
+            .swarms
+            .iter()
+            .min_by_key(|(_, swarm)| (swarm.requests, swarm.last_announce()))
+            .map(|(info_hash, _)| *info_hash);
+
+        if let Some(info_hash) = evicted {
+            self.swarms.remove(&info_hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn peer(port: u16) -> Endpoint {
+        Endpoint::new("10.0.0.1", port).unwrap()
+    }
+
+    fn ports(mut peers: Vec<Endpoint>) -> Vec<u16> {
+        peers.sort_unstable_by_key(|peer| peer.port);
+        peers.iter().map(|peer| peer.port).collect()
+    }
+
+    #[test]
+    fn test_deduplicate_announces() {
+        let mut store = PeerStore::new();
+        let info_hash = HashId::new([1; 20]);
+        let now = Instant::now();
+
+        store.announce(info_hash, peer(6881), now);
+        store.announce(info_hash, peer(6881), now + MINUTE);
+        store.announce(info_hash, peer(6882), now);
+
+        assert_eq!(ports(store.get(&info_hash, now + MINUTE)), vec![6881, 6882]);
+        assert_eq!(store.len(), 2);
+        assert!(store.get(&HashId::new([2; 20]), now).is_empty());
+    }
+
+    #[test]
+    fn test_announces_expire_unless_renewed() {
+        let mut store = PeerStore::new();
+        let info_hash = HashId::new([1; 20]);
+        let now = Instant::now();
+
+        store.announce(info_hash, peer(6881), now);
+        store.announce(info_hash, peer(6882), now);
+        store.announce(info_hash, peer(6882), now + 20 * MINUTE);
+
+        let later = now + PeerStore::TTL;
+        assert_eq!(store.get(&info_hash, later), vec![peer(6882)]);
+
+        assert_eq!(store.expire(later), 1);
+        assert_eq!(store.len(), 1);
+
+        assert_eq!(store.expire(later + 20 * MINUTE), 1);
+        assert!(store.is_empty());
+        assert_eq!(store.info_hashes(), 0);
+    }
+
+    #[test]
+    fn test_configure_ttl() {
+        let mut store = PeerStore::new();
+        let info_hash = HashId::new([1; 20]);
+        let now = Instant::now();
+
+        store.set_ttl(5 * MINUTE);
+        store.announce(info_hash, peer(6881), now);
+
+        assert_eq!(store.get(&info_hash, now + 4 * MINUTE).len(), 1);
+        assert!(store.get(&info_hash, now + 5 * MINUTE).is_empty());
+    }
+
+    #[test]
+    fn test_evict_oldest_peer_of_full_info_hash() {
+        let mut store = PeerStore::new();
+        let info_hash = HashId::new([1; 20]);
+        let now = Instant::now();
+
+        store.set_limits(10, 3);
+        store.announce(info_hash, peer(1), now + MINUTE);
+        store.announce(info_hash, peer(2), now);
+        store.announce(info_hash, peer(3), now + 2 * MINUTE);
+
+        // renewing a stored peer doesn't evict anyone
+        store.announce(info_hash, peer(3), now + 3 * MINUTE);
+        assert_eq!(store.len(), 3);
+
+        store.announce(info_hash, peer(4), now + 3 * MINUTE);
+        assert_eq!(ports(store.get(&info_hash, now + 3 * MINUTE)), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn test_evict_least_requested_info_hash() {
+        let mut store = PeerStore::new();
+        let now = Instant::now();
+        let hash = |n| HashId::new([n; 20]);
+
+        store.set_limits(3, 10);
+        store.announce(hash(1), peer(1), now);
+        store.announce(hash(2), peer(1), now + MINUTE);
+        store.announce(hash(3), peer(1), now);
+        store.get(&hash(1), now);
+
+        // 3 is requested as rarely as 2 but was announced longer ago
+        store.announce(hash(4), peer(1), now + MINUTE);
+        assert_eq!(store.info_hashes(), 3);
+        assert!(store.get(&hash(3), now).is_empty());
+
+        // announcing to a stored info hash never evicts another one
+        store.announce(hash(1), peer(2), now + MINUTE);
+        assert_eq!(store.info_hashes(), 3);
+
+        store.get(&hash(4), now);
+        store.announce(hash(5), peer(1), now + MINUTE);
+        assert!(store.get(&hash(2), now).is_empty());
+        assert_eq!(store.get(&hash(1), now).len(), 2);
+    }
+
+    #[test]
+    fn test_return_random_subset_of_peers() {
+        let mut store = PeerStore::new();
+        let info_hash = HashId::new([1; 20]);
+        let now = Instant::now();
+
+        for port in 0..300 {
+            store.announce(info_hash, peer(port), now);
+        }
+
+        let first = store.get(&info_hash, now);
+        let second = store.get(&info_hash, now);
+
+        assert_eq!(first.len(), PeerStore::MAX_VALUES);
+        assert_eq!(second.len(), PeerStore::MAX_VALUES);
+        assert_ne!(ports(first), ports(second));
+    }
+}
@@ -1,20 +1,35 @@
 use crate::structs::node::Endpoint;
 use rand::Rng;
 use sha1::{Sha1, Digest};
+use std::time::{Duration, Instant};
 
 type Secret = [u8; 32];
 
+pub type Token = Vec<u8>;
+
+/// Tokens are signed with the current secret and the one before it is still accepted,
+/// so every token stays valid for one to two rotation intervals.
 #[derive(Debug)]
 pub struct TokenAuthority {
 	current_secret: Secret,
-	last_secret: Secret
+	last_secret: Secret,
+	last_rotation: Instant
+}
+
+impl Default for TokenAuthority {
+	fn default() -> TokenAuthority {
+		TokenAuthority::new(Instant::now())
+	}
 }
 
 impl TokenAuthority {
-	pub fn new() -> TokenAuthority {
+	pub const ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+	pub fn new(now: Instant) -> TokenAuthority {
 		TokenAuthority {
 			current_secret: TokenAuthority::random_secret(),
-			last_secret: TokenAuthority::random_secret()
+			last_secret: TokenAuthority::random_secret(),
+			last_rotation: now
 		}
 	}
 
@@ -23,16 +38,26 @@ impl TokenAuthority {
 		self.current_secret = TokenAuthority::random_secret();
 	}
 
-	pub fn sign(&self, data: &Endpoint) -> String {
+	pub fn rotate_if_due(&mut self, now: Instant) -> bool {
+		if now.saturating_duration_since(self.last_rotation) < TokenAuthority::ROTATION_INTERVAL {
+			return false;
+		}
+
+		self.rotate();
+		self.last_rotation = now;
+		true
+	}
+
+	pub fn sign(&self, data: &Endpoint) -> Token {
 		TokenAuthority::sign_with(data, &self.current_secret)
 	}
 
-	pub fn verify(&self, token: &String, data: &Endpoint) -> bool {
-		if &TokenAuthority::sign_with(data, &self.current_secret) == token {
+	pub fn verify(&self, token: &[u8], data: &Endpoint) -> bool {
+		if TokenAuthority::sign_with(data, &self.current_secret) == token {
 			return true;
 		}
 
-		if &TokenAuthority::sign_with(data, &self.last_secret) == token {
+		if TokenAuthority::sign_with(data, &self.last_secret) == token {
 			return true;
 		}
 
@@ -43,23 +68,24 @@ impl TokenAuthority {
 		rand::thread_rng().gen()
 	}
 
-	fn sign_with(data: &Endpoint, secret: &Secret) -> String {
+	fn sign_with(data: &Endpoint, secret: &Secret) -> Token {
 		let mut hasher = Sha1::new();
 		let mut input = secret.to_vec();
 
 		input.extend_from_slice(&data.addr.octets());
 		hasher.input(input);
 
-		hex::encode(hasher.result())
+		hasher.result().to_vec()
 	}
 }
 
+#[cfg(test)]
 mod tests {
 	use super::*;
 
 	#[test]
 	fn test_sign_token() {
-		let mut signer = TokenAuthority::new();
+		let mut signer = TokenAuthority::default();
 		let old_secret = signer.current_secret;
 
 		let data1 = Endpoint::new("127.0.0.1", 4444).unwrap();
@@ -70,13 +96,29 @@ mod tests {
 
 		assert!(signer.verify(&token1, &data1));
 		assert!(signer.verify(&token2, &data2));
-		assert_eq!(signer.verify(&token1, &data2), false);
+		assert!(!signer.verify(&token1, &data2));
 
 		signer.rotate();
 		assert!(signer.verify(&token1, &data1));
 		assert_eq!(signer.last_secret, old_secret);
 
 		signer.rotate();
-		assert_eq!(signer.verify(&token1, &data1), false);
+		assert!(!signer.verify(&token1, &data1));
+	}
+
+	#[test]
+	fn test_rotate_every_interval() {
+		let start = Instant::now();
+		let mut signer = TokenAuthority::new(start);
+		let data = Endpoint::new("127.0.0.1", 4444).unwrap();
+		let token = signer.sign(&data);
+
+		assert!(!signer.rotate_if_due(start + Duration::from_secs(299)));
+		assert!(signer.rotate_if_due(start + TokenAuthority::ROTATION_INTERVAL));
+		assert!(signer.verify(&token, &data));
+
+		assert!(!signer.rotate_if_due(start + Duration::from_secs(599)));
+		assert!(signer.rotate_if_due(start + TokenAuthority::ROTATION_INTERVAL * 2));
+		assert!(!signer.verify(&token, &data));
 	}
-}
\ No newline at end of file
+}
@@ -0,0 +1,172 @@
+use super::error::DhtError;
+use super::message::*;
+use super::node::*;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct PendingQuery {
+    pub endpoint: Endpoint,
+    pub query: Query,
+    pub sent: Instant,
+}
+
+/// Keeps track of the queries we sent until they are answered or timed out.
+#[derive(Debug)]
+pub struct Transactions {
+    next_id: u16,
+    pending: HashMap<MessageId, PendingQuery>,
+}
+
+impl Default for Transactions {
+    fn default() -> Transactions {
+        Transactions::new()
+    }
+}
+
+impl Transactions {
+    pub const TIMEOUT: Duration = Duration::from_secs(10);
+    /// Far below the 65536 ids, so there's always a free one to find.
+    pub const MAX_PENDING: usize = 4096;
+
+    pub fn new() -> Transactions {
+        Transactions {
+            next_id: rand::random(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn start(&mut self, endpoint: Endpoint, query: Query, now: Instant) -> Result<MessageId, DhtError> {
+        if self.pending.len() >= Transactions::MAX_PENDING {
+            return Err(DhtError::TooManyTransactions);
+        }
+
+        let mut id = self.next_id();
+
+        while self.pending.contains_key(&id) {
+            id = self.next_id();
+        }
+
+        self.pending.insert(id.clone(), PendingQuery { endpoint, query, sent: now });
+
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &[u8], endpoint: &Endpoint) -> Option<&PendingQuery> {
+        self.pending.get(id).filter(|pending| pending.endpoint == *endpoint)
+    }
+
+    /// Removes the query a message answers, answers from anyone but the queried endpoint are ignored.
+    pub fn finish(&mut self, id: &[u8], endpoint: &Endpoint) -> Option<PendingQuery> {
+        self.get(id, endpoint)?;
+        self.pending.remove(id)
+    }
+
+    pub fn expire(&mut self, now: Instant) -> Vec<(MessageId, PendingQuery)> {
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.saturating_duration_since(pending.sent) >= Transactions::TIMEOUT)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<MessageId>>();
+
+        expired
+            .into_iter()
+            .filter_map(|id| self.pending.remove(&id).map(|pending| (id, pending)))
+            .collect()
+    }
+
+    pub fn is_pending(&self, endpoint: &Endpoint) -> bool {
+        self.pending.values().any(|pending| pending.endpoint == *endpoint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn next_id(&mut self) -> MessageId {
+        let id = self.next_id.to_be_bytes().to_vec();
+        self.next_id = self.next_id.wrapping_add(1);
+
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::util::HashId;
+
+    fn ping() -> Query {
+        Query::Ping { id: HashId::new([1; 20]) }
+    }
+
+    fn endpoint(port: u16) -> Endpoint {
+        Endpoint::new("10.0.0.1", port).unwrap()
+    }
+
+    #[test]
+    fn test_ids_are_unique() {
+        let mut transactions = Transactions::new();
+        let now = Instant::now();
+
+        let first = transactions.start(endpoint(1), ping(), now).unwrap();
+        transactions.next_id = u16::from_be_bytes([first[0], first[1]]);
+        let second = transactions.start(endpoint(2), ping(), now).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(second.len(), 2);
+        assert_eq!(transactions.len(), 2);
+    }
+
+    #[test]
+    fn test_finish_matches_endpoint() {
+        let mut transactions = Transactions::new();
+        let id = transactions.start(endpoint(1), ping(), Instant::now()).unwrap();
+
+        assert!(transactions.finish(&id, &endpoint(2)).is_none());
+        assert!(transactions.finish(b"zz", &endpoint(1)).is_none());
+
+        let pending = transactions.finish(&id, &endpoint(1)).unwrap();
+        assert_eq!(pending.query, ping());
+        assert!(transactions.finish(&id, &endpoint(1)).is_none());
+    }
+
+    #[test]
+    fn test_expire_old_queries() {
+        let mut transactions = Transactions::new();
+        let now = Instant::now();
+
+        let old = transactions.start(endpoint(1), ping(), now).unwrap();
+        transactions.start(endpoint(2), ping(), now + Duration::from_secs(6)).unwrap();
+
+        let expired = transactions.expire(now + Duration::from_secs(11));
+
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, old);
+        assert_eq!(expired[0].1.endpoint, endpoint(1));
+        assert!(!transactions.is_pending(&endpoint(1)));
+        assert!(transactions.is_pending(&endpoint(2)));
+    }
+
+    #[test]
+    fn test_refuse_queries_beyond_the_limit() {
+        let mut transactions = Transactions::new();
+        let now = Instant::now();
+
+        for _ in 0..Transactions::MAX_PENDING {
+            transactions.start(endpoint(1), ping(), now).unwrap();
+        }
+
+        assert!(transactions.start(endpoint(1), ping(), now).is_err());
+        assert_eq!(transactions.len(), Transactions::MAX_PENDING);
+
+        transactions.expire(now + Transactions::TIMEOUT);
+        assert!(transactions.start(endpoint(1), ping(), now).is_ok());
+    }
+}
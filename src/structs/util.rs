@@ -2,38 +2,147 @@ use std::cmp::Ordering;
 use std::fmt;
 use std::ops::BitXor;
 
+use rand::Rng;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
 use super::error::*;
 
-#[derive(Copy, Clone, Eq, Debug, Hash)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
 pub struct HashId {
     pub hash: [u8; 20],
 }
 
 impl HashId {
+    pub const BITS: usize = 160;
+
     pub fn new(hash: [u8; 20]) -> HashId {
         HashId { hash }
     }
 
-    pub fn from_str(input: String) -> Result<HashId, InvalidHashIdError> {
-        let vec = match hex::decode(input) {
-            Ok(vec) => vec,
-            Err(_e) => return Err(InvalidHashIdError {}),
-        };
+    pub fn random() -> HashId {
+        HashId {
+            hash: rand::thread_rng().gen(),
+        }
+    }
 
-        if vec.len() != 20 {
-            return Err(InvalidHashIdError {});
+    pub fn from_bytes(input: &[u8]) -> Result<HashId, DhtError> {
+        if input.len() != 20 {
+            return Err(DhtError::InvalidHashId);
         }
 
         let mut hash = [0; 20];
-        for i in 0..20 {
-            hash[i] = vec[i];
-        }
+        hash.copy_from_slice(input);
 
         Ok(HashId { hash })
     }
 
-    pub fn to_str(&self) -> String {
-        hex::encode(self.hash)
+    pub fn shared_prefix_len(&self, other: &HashId) -> usize {
+        for n in 0..20 {
+            let difference = self.hash[n] ^ other.hash[n];
+
+            if difference != 0 {
+                return n * 8 + difference.leading_zeros() as usize;
+            }
+        }
+
+        HashId::BITS
+    }
+
+    pub fn bit(&self, index: usize) -> bool {
+        self.hash[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn flip_bit(&self, index: usize) -> HashId {
+        let mut hash = self.hash;
+        hash[index / 8] ^= 0x80 >> (index % 8);
+
+        HashId { hash }
+    }
+
+    /// Keeps the first `prefix_len` bits and randomizes all others.
+    pub fn random_with_prefix(&self, prefix_len: usize) -> HashId {
+        let mut hash = HashId::random().hash;
+
+        for (n, byte) in hash.iter_mut().enumerate() {
+            let mask = HashId::prefix_mask(prefix_len, n);
+            *byte = (self.hash[n] & mask) | (*byte & !mask);
+        }
+
+        HashId { hash }
+    }
+
+    /// Keeps the first `prefix_len` bits and clears all others.
+    pub fn masked(&self, prefix_len: usize) -> HashId {
+        let mut hash = self.hash;
+
+        for (n, byte) in hash.iter_mut().enumerate() {
+            *byte &= HashId::prefix_mask(prefix_len, n);
+        }
+
+        HashId { hash }
+    }
+
+    /// Keeps the first `prefix_len` bits and sets all others.
+    pub fn filled(&self, prefix_len: usize) -> HashId {
+        let mut hash = self.hash;
+
+        for (n, byte) in hash.iter_mut().enumerate() {
+            *byte |= !HashId::prefix_mask(prefix_len, n);
+        }
+
+        HashId { hash }
+    }
+
+    fn prefix_mask(prefix_len: usize, byte: usize) -> u8 {
+        match prefix_len.saturating_sub(byte * 8) {
+            0 => 0,
+            bits if bits >= 8 => 0xff,
+            bits => 0xff << (8 - bits),
+        }
+    }
+
+    /// Only the tests walk the id space in order.
+    #[cfg(test)]
+    pub fn successor(&self) -> HashId {
+        let mut hash = self.hash;
+
+        for byte in hash.iter_mut().rev() {
+            let (next, overflow) = byte.overflowing_add(1);
+            *byte = next;
+
+            if !overflow {
+                break;
+            }
+        }
+
+        HashId { hash }
+    }
+}
+
+impl Serialize for HashId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.hash)
+    }
+}
+
+impl<'de> Deserialize<'de> for HashId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<HashId, D::Error> {
+        struct HashIdVisitor;
+
+        impl<'de> Visitor<'de> for HashIdVisitor {
+            type Value = HashId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a 20 byte string")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<HashId, E> {
+                HashId::from_bytes(v).map_err(|_| E::invalid_length(v.len(), &self))
+            }
+        }
+
+        deserializer.deserialize_bytes(HashIdVisitor)
     }
 }
 
@@ -43,8 +152,8 @@ impl BitXor for HashId {
     fn bitxor(self, other: HashId) -> HashId {
         let mut result: [u8; 20] = [0; 20];
 
-        for n in 0..20 {
-            result[n] = self.hash[n] ^ other.hash[n];
+        for (n, byte) in result.iter_mut().enumerate() {
+            *byte = self.hash[n] ^ other.hash[n];
         }
 
         HashId { hash: result }
@@ -73,12 +182,6 @@ impl PartialOrd for HashId {
     }
 }
 
-impl PartialEq for HashId {
-    fn eq(&self, other: &Self) -> bool {
-        self.hash == other.hash
-    }
-}
-
 impl fmt::Display for HashId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", hex::encode(self.hash))
@@ -89,6 +192,15 @@ impl fmt::Display for HashId {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_from_bytes() {
+        let hash = HashId::from_bytes(&[17; 20]).unwrap();
+        assert_eq!(hash, HashId::new([17; 20]));
+
+        assert!(HashId::from_bytes(&[17; 19]).is_err());
+        assert!(HashId::from_bytes(&[17; 21]).is_err());
+    }
+
     #[test]
     fn test_equal_to_itself() {
         let hash = HashId::new([1; 20]);
@@ -108,7 +220,7 @@ mod tests {
         let hash1 = HashId::new([1; 20]);
         let hash2 = HashId::new([0; 20]);
 
-        assert_eq!(hash1 == hash2, false);
+        assert!(hash1 != hash2);
     }
 
     #[test]
@@ -117,7 +229,7 @@ mod tests {
         let hash2 = HashId::new([0; 20]);
 
         assert!(hash1 > hash2);
-        assert_eq!(hash1 <= hash2, false);
+        assert!(hash2 <= hash1);
     }
 
     #[test]
@@ -148,4 +260,65 @@ mod tests {
 
         assert!(hash1 ^ hash2 == correct);
     }
+
+    #[test]
+    fn test_shared_prefix_len() {
+        let hash = HashId::new([0; 20]);
+
+        assert_eq!(hash.shared_prefix_len(&hash), 160);
+        assert_eq!(hash.shared_prefix_len(&HashId::new([255; 20])), 0);
+        assert_eq!(hash.shared_prefix_len(&HashId::new([1; 20])), 7);
+        assert_eq!(hash.shared_prefix_len(&hash.flip_bit(100)), 100);
+    }
+
+    #[test]
+    fn test_bits() {
+        let hash = HashId::new([0; 20]).flip_bit(0).flip_bit(9);
+
+        assert!(hash.bit(0));
+        assert!(!hash.bit(1));
+        assert!(hash.bit(9));
+        assert_eq!(hash.hash[0], 0x80);
+        assert_eq!(hash.hash[1], 0x40);
+    }
+
+    #[test]
+    fn test_mask_and_fill_prefix() {
+        let hash = HashId::new([0xaa; 20]);
+
+        assert_eq!(hash.masked(0), HashId::new([0; 20]));
+        assert_eq!(hash.masked(160), hash);
+        assert_eq!(hash.filled(0), HashId::new([255; 20]));
+        assert_eq!(hash.filled(160), hash);
+
+        let masked = hash.masked(12);
+        assert_eq!(masked.hash[0..3], [0xaa, 0xa0, 0]);
+
+        let filled = hash.filled(12);
+        assert_eq!(filled.hash[0..3], [0xaa, 0xaf, 0xff]);
+    }
+
+    #[test]
+    fn test_successor() {
+        let mut expected = [0; 20];
+        expected[18] = 1;
+
+        let mut id = [0; 20];
+        id[19] = 255;
+
+        assert_eq!(HashId::new(id).successor(), HashId::new(expected));
+        assert_eq!(HashId::new([0; 20]).successor().hash[19], 1);
+    }
+
+    #[test]
+    fn test_random_with_prefix() {
+        let hash = HashId::new([0xaa; 20]);
+
+        for prefix_len in [0, 3, 12, 159, 160].iter() {
+            let random = hash.random_with_prefix(*prefix_len);
+            assert!(random.shared_prefix_len(&hash) >= *prefix_len);
+        }
+
+        assert_eq!(hash.random_with_prefix(160), hash);
+    }
 }